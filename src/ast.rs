@@ -14,7 +14,7 @@ impl Statement for ExpressionStatement {}
 pub trait IntoStatement<T: ?Sized> {
     fn into_statement(self: Rc<Self>) -> Rc<T>;
 }
-impl<'a, T: Statement + 'static> IntoStatement<dyn Statement + 'static> for T {
+impl<T: Statement + 'static> IntoStatement<dyn Statement + 'static> for T {
     fn into_statement(self: Rc<Self>) -> Rc<dyn Statement + 'static> {
         Rc::new(ExpressionStatement {
             expr: self as Rc<dyn Statement>,
@@ -36,6 +36,10 @@ impl Expression for Bool {}
 pub struct Int(pub i64);
 impl Expression for Int {}
 
+#[derive(Debug)]
+pub struct Str(pub String);
+impl Expression for Str {}
+
 #[derive(Debug)]
 pub enum LetPattern {
     Ident(String),
@@ -46,7 +50,12 @@ pub enum LetPattern {
 #[derive(Debug)]
 pub struct LetStatement {
     pub pattern: Rc<LetPattern>,
+    // `let mut` - the bindings can be assigned to
+    pub mutable: bool,
     pub expr: Rc<dyn Expression>,
+    // `let ... else { }` - runs instead of panicking when the pattern doesn't match, and
+    // must not finish normally.
+    pub else_body: Option<Rc<Block>>,
 }
 impl Statement for LetStatement {}
 
@@ -84,6 +93,8 @@ pub enum BooleanOperator {
     Xor,
 }
 
+// The grammar currently parses `.` and `[]` as part of a LocationChain.
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct DotExpression {
     pub base: Rc<dyn Expression>,
@@ -91,6 +102,7 @@ pub struct DotExpression {
 }
 impl Expression for DotExpression {}
 
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct IndexExpression {
     pub base: Rc<dyn Expression>,
@@ -141,6 +153,8 @@ impl Expression for IfExpression {}
 
 #[derive(Debug, Clone)]
 pub struct IfPart {
+    // for `if let`, the cond is the value to match against the pattern
+    pub pattern: Option<Rc<LetPattern>>,
     pub cond: Rc<dyn Expression>,
     pub body: Rc<Block>,
 }
@@ -151,10 +165,37 @@ pub struct LoopExpression {
 }
 impl Expression for LoopExpression {}
 
+// Sugar for handling the yield effect, see ForIterate for how values are iterated.
+#[derive(Debug)]
+pub struct ForExpression {
+    pub pattern: Rc<LetPattern>,
+    pub expr: Rc<dyn Expression>,
+    // the block of the yield handler, which is the loop's body desugared to `{ continue with body }`
+    pub block: Rc<Block>,
+}
+impl Expression for ForExpression {}
+
+#[derive(Debug)]
+pub struct MatchExpression {
+    pub expr: Rc<dyn Expression>,
+    pub arms: Vec<MatchArm>,
+}
+impl Expression for MatchExpression {}
+
+#[derive(Debug)]
+pub struct MatchArm {
+    pub pattern: ListSubPattern,
+    pub guard: Option<Rc<dyn Expression>>,
+    pub body: Rc<Block>,
+}
+
 #[derive(Debug)]
 pub struct Function {
     pub body: Rc<Block>,
     pub pattern: Rc<ListPattern>,
+    // parameters after a `*`, which can only be passed as keyword arguments. They are
+    // destructured from the keyword arguments like an object, eg. fn(a, *, b, c = 1, ...rest)
+    pub keyword_pattern: Option<Rc<ObjectPattern>>,
 }
 impl Expression for Function {}
 
@@ -176,10 +217,20 @@ impl Expression for Block {}
 #[derive(Debug)]
 pub struct FunctionInvocation {
     pub base: Rc<dyn Expression>,
-    pub elems: Vec<ListElem>,
+    pub elems: Vec<CallElem>,
 }
 impl Expression for FunctionInvocation {}
 
+// obj->method(args) - calls the method stored on obj under the key "method", or else
+// a function called "method" in scope, with obj as the first parameter.
+#[derive(Debug)]
+pub struct MethodCall {
+    pub base: Rc<dyn Expression>,
+    pub method: String,
+    pub elems: Vec<CallElem>,
+}
+impl Expression for MethodCall {}
+
 #[derive(Debug)]
 pub struct Object {
     pub elems: Vec<ObjectElem>,
@@ -189,6 +240,8 @@ impl Expression for Object {}
 #[derive(Debug)]
 pub enum ObjectElem {
     Kv(String, Rc<dyn Expression>),
+    // computed key eg. { [sym]: 1 }
+    Computed(Rc<dyn Expression>, Rc<dyn Expression>),
     Spread(Rc<dyn Expression>),
 }
 
@@ -204,6 +257,15 @@ pub enum ListElem {
     Elem(Rc<dyn Expression>),
 }
 
+// An argument in a function call
+#[derive(Debug)]
+pub enum CallElem {
+    Spread(Rc<dyn Expression>),
+    Elem(Rc<dyn Expression>),
+    // eg. f(a = 1)
+    Keyword(String, Rc<dyn Expression>),
+}
+
 #[derive(Debug)]
 pub struct Handle {
     pub expr: Rc<dyn Expression>,
@@ -213,14 +275,15 @@ impl Expression for Handle {}
 
 #[derive(Debug, Clone)]
 pub struct HandleMatch {
-    pub symbol: String,
-    pub param: String,
+    pub symbol: Rc<dyn Expression>,
+    // arms for the same symbol are tried in order until one's pattern matches the effect's value
+    pub pattern: Rc<LetPattern>,
     pub block: Rc<Block>,
 }
 
 #[derive(Debug)]
 pub struct SendExpr {
-    pub symbol: String,
+    pub symbol: Rc<dyn Expression>,
     pub expr: Option<Rc<dyn Expression>>,
 }
 impl Expression for SendExpr {}
@@ -237,6 +300,12 @@ pub struct Break {
 }
 impl Expression for Break {}
 
+#[derive(Debug)]
+pub struct Return {
+    pub expr: Option<Rc<dyn Expression>>,
+}
+impl Expression for Return {}
+
 #[derive(Debug)]
 pub struct LocationChain {
     pub base: LocationChainBase,
@@ -271,6 +340,10 @@ pub enum ListSubPattern {
     Ident(String),
     List(Box<ListPattern>),
     Object(Box<ObjectPattern>),
+    // eg. `b = 2`. The default is only evaluated when there is no value to destructure.
+    Default(Rc<ListSubPattern>, Rc<dyn Expression>),
+    // eg. `0` or `^sym`. Binds nothing, the value must be equal to the literal.
+    Literal(PatternKey),
 }
 
 #[derive(Debug)]
@@ -286,11 +359,24 @@ pub struct ListPattern {
     pub spread_and_after_patterns: Option<(SpreadPattern, Vec<ListSubPattern>)>,
 }
 
+// The key of an object sub-pattern written in square brackets, eg. { [sym]: value },
+// or a literal sub-pattern, eg. [0, ^sym]
+#[derive(Debug)]
+pub enum PatternKey {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    // the key is the value of a binding, eg. a symbol
+    Ident(String),
+}
+
 #[derive(Debug)]
 pub enum ObjectSubPattern {
     Ident(String),
     List(String, Box<ListPattern>),
     Object(String, Box<ObjectPattern>),
+    Keyed(PatternKey, ListSubPattern),
 }
 
 #[derive(Debug)]
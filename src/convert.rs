@@ -0,0 +1,550 @@
+// Conversions between Kal values and Rust types, using serde.
+//
+// Structs and maps become objects, sequences and tuples become lists, and `None` and `()`
+// become null. Enums use serde's externally tagged representation: unit variants are strings,
+// and other variants are objects with a single key, eg. `{ Circle: { r: 1 } }`.
+
+use crate::interpreter::{Key, Value};
+use crate::Error;
+use serde::{
+    de::{self, DeserializeOwned, IntoDeserializer},
+    ser::{self, Serialize},
+    Deserializer as _,
+};
+use std::{collections::HashMap, fmt::Display, rc::Rc};
+
+/// Convert a Rust value into a Kal value.
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value, Error> {
+    value.serialize(Serializer)
+}
+
+/// Convert a Kal value into a Rust value.
+pub fn from_value<T: DeserializeOwned>(value: &Value) -> Result<T, Error> {
+    T::deserialize(Deserializer { value })
+}
+
+/// Types which can be returned to Kal code, eg. from native functions.
+pub trait IntoValue {
+    fn into_value(self) -> Result<Value, Error>;
+}
+
+/// Types which can be taken from Kal values, eg. the arguments of native functions.
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> Result<Self, Error>;
+}
+
+impl<T: Serialize> IntoValue for T {
+    fn into_value(self) -> Result<Value, Error> {
+        to_value(&self)
+    }
+}
+
+impl<T: DeserializeOwned> FromValue for T {
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        from_value(value)
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::runtime("conversion", msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::runtime("conversion", msg.to_string())
+    }
+}
+
+fn str_value(s: &str) -> Value {
+    Value::Str(Rc::new(s.to_owned()))
+}
+
+fn tagged(variant: &str, value: Value) -> Value {
+    let mut obj = HashMap::new();
+    obj.insert(Key::Str(variant.to_owned()), value);
+    Value::Object(Rc::new(obj))
+}
+
+struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = SerializeList;
+    type SerializeTuple = SerializeList;
+    type SerializeTupleStruct = SerializeList;
+    type SerializeTupleVariant = SerializeList;
+    type SerializeMap = SerializeObject;
+    type SerializeStruct = SerializeObject;
+    type SerializeStructVariant = SerializeObject;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+        Ok(Value::Bool(v))
+    }
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+        Ok(Value::Int(v.into()))
+    }
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+        Ok(Value::Int(v.into()))
+    }
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+        Ok(Value::Int(v.into()))
+    }
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+        Ok(Value::Int(v))
+    }
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+        Ok(Value::Int(v.into()))
+    }
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        Ok(Value::Int(v.into()))
+    }
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        Ok(Value::Int(v.into()))
+    }
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+        match i64::try_from(v) {
+            Ok(v) => Ok(Value::Int(v)),
+            Err(_) => Err(ser::Error::custom(format!(
+                "{} is too big to be a Kal int.",
+                v
+            ))),
+        }
+    }
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+        self.serialize_f64(v.into())
+    }
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+        Ok(Value::Float(v))
+    }
+    fn serialize_char(self, v: char) -> Result<Value, Error> {
+        Ok(Value::Str(Rc::new(v.to_string())))
+    }
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        Ok(str_value(v))
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+        Ok(Value::List(Rc::new(
+            v.iter().map(|b| Value::Int((*b).into())).collect(),
+        )))
+    }
+    fn serialize_none(self) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+        Ok(Value::Null)
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Value, Error> {
+        Ok(str_value(variant))
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        Ok(tagged(variant, value.serialize(self)?))
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeList, Error> {
+        Ok(SerializeList {
+            variant: None,
+            elems: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+    fn serialize_tuple(self, len: usize) -> Result<SerializeList, Error> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeList, Error> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeList, Error> {
+        Ok(SerializeList {
+            variant: Some(variant),
+            elems: Vec::with_capacity(len),
+        })
+    }
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeObject, Error> {
+        Ok(SerializeObject {
+            variant: None,
+            entries: HashMap::with_capacity(len.unwrap_or(0)),
+            next_key: None,
+        })
+    }
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeObject, Error> {
+        self.serialize_map(Some(len))
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeObject, Error> {
+        Ok(SerializeObject {
+            variant: Some(variant),
+            entries: HashMap::with_capacity(len),
+            next_key: None,
+        })
+    }
+}
+
+struct SerializeList {
+    // the variant to tag the list with, for tuple variants
+    variant: Option<&'static str>,
+    elems: Vec<Value>,
+}
+
+impl SerializeList {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.elems.push(value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        let list = Value::List(Rc::new(self.elems));
+        Ok(match self.variant {
+            Some(variant) => tagged(variant, list),
+            None => list,
+        })
+    }
+}
+
+impl ser::SerializeSeq for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeList {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+struct SerializeObject {
+    // the variant to tag the object with, for struct variants
+    variant: Option<&'static str>,
+    entries: HashMap<Key, Value>,
+    next_key: Option<Key>,
+}
+
+impl SerializeObject {
+    fn insert<T: Serialize + ?Sized>(&mut self, key: Key, value: &T) -> Result<(), Error> {
+        self.entries.insert(key, value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Value, Error> {
+        let obj = Value::Object(Rc::new(self.entries));
+        Ok(match self.variant {
+            Some(variant) => tagged(variant, obj),
+            None => obj,
+        })
+    }
+}
+
+impl ser::SerializeMap for SerializeObject {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        let key = key.serialize(Serializer)?;
+        match Key::try_from_value(&key) {
+            Some(key) => {
+                self.next_key = Some(key);
+                Ok(())
+            }
+            None => Err(ser::Error::custom(format!(
+                "Can't use {:?} as an object key.",
+                key
+            ))),
+        }
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .next_key
+            .take()
+            .expect("serialize_value was called before serialize_key.");
+        self.insert(key, value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeObject {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(Key::Str(key.to_owned()), value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeObject {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(Key::Str(key.to_owned()), value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+struct Deserializer<'a> {
+    value: &'a Value,
+}
+
+fn unsupported(value: &Value) -> Error {
+    de::Error::custom(format!("Can't convert {:?} to a Rust value.", value))
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Deserializer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Null => visitor.visit_unit(),
+            Value::Bool(b) => visitor.visit_bool(*b),
+            Value::Int(i) => visitor.visit_i64(*i),
+            Value::Float(f) => visitor.visit_f64(*f),
+            Value::Str(s) => visitor.visit_str(s),
+            Value::List(list) => visitor.visit_seq(ListAccess { iter: list.iter() }),
+            Value::Object(obj) => visitor.visit_map(ObjectAccess {
+                iter: obj.iter(),
+                next_value: None,
+            }),
+            value => Err(unsupported(value)),
+        }
+    }
+
+    fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Str(variant) => visitor.visit_enum(variant.as_str().into_deserializer()),
+            Value::Object(obj) if obj.len() == 1 => {
+                let (variant, value) = obj.iter().next().unwrap();
+                let variant = match variant {
+                    Key::Str(variant) => variant.as_str(),
+                    key => {
+                        return Err(de::Error::custom(format!(
+                            "Can't use {:?} as an enum variant.",
+                            key
+                        )))
+                    }
+                };
+                visitor.visit_enum(EnumAccess { variant, value })
+            }
+            value => Err(de::Error::custom(format!(
+                "Expected a string or an object with one key for an enum, got {:?}.",
+                value
+            ))),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct ListAccess<'a> {
+    iter: std::slice::Iter<'a, Value>,
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for ListAccess<'a> {
+    type Error = Error;
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        match self.iter.next() {
+            Some(value) => seed.deserialize(Deserializer { value }).map(Some),
+            None => Ok(None),
+        }
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct ObjectAccess<'a> {
+    iter: std::collections::hash_map::Iter<'a, Key, Value>,
+    next_value: Option<&'a Value>,
+}
+
+impl<'de, 'a> de::MapAccess<'de> for ObjectAccess<'a> {
+    type Error = Error;
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        let (key, value) = match self.iter.next() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        self.next_value = Some(value);
+        let key = match key {
+            Key::Null => Value::Null,
+            Key::Bool(b) => Value::Bool(*b),
+            Key::Int(i) => Value::Int(*i),
+            Key::Str(s) => str_value(s),
+            Key::Symbol(_) => {
+                return Err(de::Error::custom(
+                    "Can't convert a symbol key to a Rust value.",
+                ))
+            }
+        };
+        seed.deserialize(Deserializer { value: &key }).map(Some)
+    }
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = self
+            .next_value
+            .take()
+            .expect("next_value_seed was called before next_key_seed.");
+        seed.deserialize(Deserializer { value })
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.iter.len())
+    }
+}
+
+struct EnumAccess<'a> {
+    variant: &'a str,
+    value: &'a Value,
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for EnumAccess<'a> {
+    type Error = Error;
+    type Variant = Deserializer<'a>;
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Deserializer<'a>), Error> {
+        let variant = seed.deserialize(self.variant.into_deserializer())?;
+        Ok((variant, Deserializer { value: self.value }))
+    }
+}
+
+impl<'de, 'a> de::VariantAccess<'de> for Deserializer<'a> {
+    type Error = Error;
+    fn unit_variant(self) -> Result<(), Error> {
+        match self.value {
+            Value::Null => Ok(()),
+            value => Err(de::Error::custom(format!(
+                "Expected null for a unit variant, got {:?}.",
+                value
+            ))),
+        }
+    }
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+    fn tuple_variant<V: de::Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_any(visitor)
+    }
+    fn struct_variant<V: de::Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_any(visitor)
+    }
+}
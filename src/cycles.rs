@@ -0,0 +1,154 @@
+use crate::interpreter::{Binding, Closure, Key, Scope, Value};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+// Kal only uses reference counting, so values which refer to themselves are never freed.
+// Every cycle has to pass through a `let mut` cell, because that is the only way to store a
+// value somewhere which already existed when the value was created. eg.
+//
+//     let mut var = 5;
+//     let f = fn(x) { var = x; };
+//     f(f)
+//
+// f's scope chain holds the cell for var, which now holds f.
+//
+// The collector uses trial deletion. Starting from every live cell, it finds all of the
+// reference counted nodes reachable from them and counts the references between those nodes.
+// A node with more references than that is held by something outside the graph (a value stack,
+// the current scope, an effect...) so it is alive, along with everything it refers to.
+// Cells that aren't alive are only kept alive by cycles, so they are cleared to break them.
+
+enum Node {
+    Cell(Rc<RefCell<Value>>),
+    Scope(Rc<Scope>),
+    Closure(Rc<Closure>),
+    List(Rc<Vec<Value>>),
+    Object(Rc<HashMap<Key, Value>>),
+}
+
+impl Node {
+    fn from_value(value: &Value) -> Option<Node> {
+        match value {
+            Value::List(list) => Some(Node::List(list.clone())),
+            Value::Object(obj) => Some(Node::Object(obj.clone())),
+            Value::Closure(closure) => Some(Node::Closure(closure.clone())),
+            // Effects are treated as opaque, so anything they refer to is considered alive.
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> *const () {
+        match self {
+            Node::Cell(cell) => Rc::as_ptr(cell) as *const (),
+            Node::Scope(scope) => Rc::as_ptr(scope) as *const (),
+            Node::Closure(closure) => Rc::as_ptr(closure) as *const (),
+            Node::List(list) => Rc::as_ptr(list) as *const (),
+            Node::Object(obj) => Rc::as_ptr(obj) as *const (),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Cell(cell) => Rc::strong_count(cell),
+            Node::Scope(scope) => Rc::strong_count(scope),
+            Node::Closure(closure) => Rc::strong_count(closure),
+            Node::List(list) => Rc::strong_count(list),
+            Node::Object(obj) => Rc::strong_count(obj),
+        }
+    }
+
+    fn children(&self) -> Vec<Node> {
+        match self {
+            Node::Cell(cell) => Node::from_value(&cell.borrow()).into_iter().collect(),
+            Node::Scope(scope) => {
+                let bindings = scope.bindings.values().filter_map(|binding| match binding {
+                    Binding::Value(value) => Node::from_value(value),
+                    Binding::Cell(cell) => Some(Node::Cell(cell.clone())),
+                });
+                let parent = scope
+                    .parent
+                    .iter()
+                    .map(|parent| Node::Scope(parent.clone()));
+                bindings.chain(parent).collect()
+            }
+            Node::Closure(closure) => vec![Node::Scope(closure.parent_scope.clone())],
+            Node::List(list) => list.iter().filter_map(Node::from_value).collect(),
+            Node::Object(obj) => obj.values().filter_map(Node::from_value).collect(),
+        }
+    }
+}
+
+struct GraphNode {
+    node: Node,
+    internal_refs: usize,
+    children: Vec<*const ()>,
+}
+
+// Break the reference cycles between the given cells, returning how many cells were cleared.
+pub fn collect_cycles(cells: Vec<Rc<RefCell<Value>>>) -> usize {
+    let mut graph: HashMap<*const (), GraphNode> = HashMap::new();
+    let mut to_visit = Vec::new();
+    for cell in cells {
+        let node = Node::Cell(cell);
+        let id = node.id();
+        graph.entry(id).or_insert_with(|| {
+            to_visit.push(id);
+            GraphNode {
+                node,
+                internal_refs: 0,
+                children: Vec::new(),
+            }
+        });
+    }
+
+    while let Some(id) = to_visit.pop() {
+        let children = graph[&id].node.children();
+        let mut child_ids = Vec::with_capacity(children.len());
+        for child in children {
+            let child_id = child.id();
+            graph
+                .entry(child_id)
+                .or_insert_with(|| {
+                    to_visit.push(child_id);
+                    GraphNode {
+                        node: child,
+                        internal_refs: 0,
+                        children: Vec::new(),
+                    }
+                })
+                .internal_refs += 1;
+            child_ids.push(child_id);
+        }
+        graph.get_mut(&id).unwrap().children = child_ids;
+    }
+
+    // The graph holds one reference to every node itself.
+    let mut alive: Vec<*const ()> = graph
+        .iter()
+        .filter(|(_, graph_node)| graph_node.node.strong_count() > graph_node.internal_refs + 1)
+        .map(|(id, _)| *id)
+        .collect();
+    let mut reachable = HashSet::new();
+    while let Some(id) = alive.pop() {
+        if reachable.insert(id) {
+            alive.extend(graph[&id].children.iter().copied());
+        }
+    }
+
+    let mut cleared = Vec::new();
+    for (id, graph_node) in graph.iter() {
+        if let Node::Cell(cell) = &graph_node.node {
+            if !reachable.contains(id) {
+                cleared.push(cell.replace(Value::Null));
+            }
+        }
+    }
+    let num_cleared = cleared.len();
+    // Drop the graph's references before the cleared values, so the cycles are actually freed.
+    drop(graph);
+    drop(cleared);
+    num_cleared
+}
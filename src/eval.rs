@@ -1,4 +1,7 @@
-use super::{interpreter::Value, Interpreter};
+use super::{
+    interpreter::{SymbolGenerator, Value},
+    Interpreter,
+};
 use std::{
     fmt::{self, Debug},
     rc::Rc,
@@ -45,16 +48,26 @@ impl<T: Fn(&mut Interpreter)> Eval for Custom<T> {
     }
 }
 
+// The result of resolving one part of a location chain as an expression.
+pub enum Resolved {
+    Value(Value),
+    // The location is implemented by a method, which must be called with these arguments.
+    Call(Value, Vec<Value>),
+}
+
 pub trait Location: Debug {
     fn push_exprs(&self, int: &mut Interpreter);
-    fn resolve<'s, 'int>(
-        &'s self,
+    fn resolve_value(&self, int: &mut Interpreter, base: Value) -> Resolved;
+    fn resolve<'int>(
+        &self,
         pop_value: &mut dyn FnMut() -> Value,
         base: &'int Value,
+        symbols: &SymbolGenerator,
     ) -> &'int Value;
-    fn resolve_mut<'s, 'int>(
-        &'s self,
+    fn resolve_mut<'int>(
+        &self,
         pop_value: &mut dyn FnMut() -> Value,
         base: &'int mut Value,
+        symbols: &SymbolGenerator,
     ) -> &'int mut Value;
 }
@@ -2,14 +2,17 @@ use super::{
     eval::Eval,
     interpreter::{
         Closure, Effect, FunctionContext, Interpreter, Key, Scope, SubContext, SubContextType,
-        Value,
+        SymbolGenerator, Value,
     },
 };
 use crate::{
     ast::{self},
-    eval::{Custom, Location},
+    eval::{Custom, Location, Resolved},
+    intrinsics::WellKnownSymbol,
+    limits::{list_bytes, object_bytes, value_bytes},
+    tasks::{ChannelId, Wait},
 };
-use std::{collections::HashMap, iter::Peekable, rc::Rc, vec::IntoIter};
+use std::{collections::HashMap, path::PathBuf, rc::Rc};
 
 impl Eval for ast::DotExpression {
     fn eval(self: Rc<Self>, int: &mut Interpreter) {
@@ -49,6 +52,12 @@ impl Eval for ast::Object {
 
                         map.insert(Key::Str(name.clone()), value);
                     }
+                    ast::ObjectElem::Computed(_, _) => {
+                        let key = int.pop_value();
+                        let value = int.pop_value();
+
+                        map.insert(Key::from_value(&key), value);
+                    }
                     ast::ObjectElem::Spread(_) => {
                         let value = int.pop_value();
                         let value = match value {
@@ -63,6 +72,9 @@ impl Eval for ast::Object {
                 }
             }
 
+            if let Err(err) = int.allocate(object_bytes(map.len())) {
+                return send_runtime_error(int, err);
+            }
             int.push_value(Value::Object(Rc::new(map)));
         })));
 
@@ -71,6 +83,10 @@ impl Eval for ast::Object {
                 ast::ObjectElem::Kv(_, expr) => {
                     int.push_eval(expr.clone().into_eval());
                 }
+                ast::ObjectElem::Computed(key, expr) => {
+                    int.push_eval(key.clone().into_eval());
+                    int.push_eval(expr.clone().into_eval());
+                }
                 ast::ObjectElem::Spread(expr) => {
                     int.push_eval(expr.clone().into_eval());
                 }
@@ -81,6 +97,18 @@ impl Eval for ast::Object {
         "Object"
     }
 }
+fn full_compare<T: PartialOrd>(operator: ast::ComparisonOperator, left: T, right: T) -> bool {
+    use ast::ComparisonOperator::*;
+    match operator {
+        Equal => left == right,
+        NotEqual => left != right,
+        Less => left < right,
+        Greater => left > right,
+        LessEqual => left <= right,
+        GreaterEqual => left >= right,
+    }
+}
+
 impl Eval for ast::ComparisonExpression {
     fn eval(self: Rc<Self>, int: &mut Interpreter) {
         let operator = self.operator;
@@ -88,95 +116,174 @@ impl Eval for ast::ComparisonExpression {
             let left = int.pop_value();
             let right = int.pop_value();
 
-            let fail = |operator, left, right| {
+            let fail = |int: &Interpreter, operator, left: &Value, right: &Value| {
                 panic!(
-                    "Invalid comparison. Cannot apply {:?} to {:?} and {:?}",
-                    operator, left, right
+                    "Invalid comparison. Cannot apply {:?} to {} and {}",
+                    operator,
+                    int.display_nested(left),
+                    int.display_nested(right)
                 );
             };
             use ast::ComparisonOperator::*;
             use Value::*;
-            let full_compare = |operator, left, right| match operator {
-                Equal => left == right,
-                NotEqual => left != right,
-                Less => left < right,
-                Greater => left > right,
-                LessEqual => left <= right,
-                GreaterEqual => left >= right,
+
+            // objects can implement comparisons with the eq and compare symbols
+            let symbol = match operator {
+                Equal | NotEqual => WellKnownSymbol::Eq,
+                _ => WellKnownSymbol::Compare,
             };
+            if let Some(method) = left
+                .well_known_method(symbol)
+                .or_else(|| right.well_known_method(symbol))
+            {
+                int.push_eval(Rc::new(Custom::new("ComparisonMethodResult", move |int| {
+                    let result = match (operator, int.pop_value()) {
+                        (Equal, Bool(b)) => b,
+                        (NotEqual, Bool(b)) => !b,
+                        (Equal | NotEqual, result) => {
+                            panic!(
+                                "The eq method must return a bool, but it returned {}.",
+                                int.display_nested(&result)
+                            )
+                        }
+                        (operator, Int(ordering)) => full_compare(operator, ordering, 0),
+                        (_, result) => {
+                            panic!(
+                                "The compare method must return an int, but it returned {}.",
+                                int.display_nested(&result)
+                            )
+                        }
+                    };
+                    int.push_value(Value::Bool(result));
+                })));
+                call_value(int, method, vec![left, right]);
+                return;
+            }
+
             // This code is super long so that I can still take advantage of the Exhaustive Patterns error
             // for Value variants.
             let result = match &(operator, &left, &right) {
                 (Equal, Null, Null) => left == right,
                 (NotEqual, Null, Null) => left != right,
-                (operator, Null, Null) => fail(operator, left, right),
+                (operator, Null, Null) => fail(int, operator, &left, &right),
 
                 (Equal, Bool(left), Bool(right)) => left == right,
                 (NotEqual, Bool(left), Bool(right)) => left != right,
-                (operator, Bool(_), Bool(_)) => fail(operator, left, right),
+                (operator, Bool(_), Bool(_)) => fail(int, operator, &left, &right),
 
                 (operator, Int(left), Int(right)) => full_compare(*operator, left, right),
+                (operator, Float(left), Float(right)) => full_compare(*operator, left, right),
+                (operator, Int(left), Float(right)) => {
+                    full_compare(*operator, *left as f64, *right)
+                }
+                (operator, Float(left), Int(right)) => {
+                    full_compare(*operator, *left, *right as f64)
+                }
+
+                (operator, Str(left), Str(right)) => full_compare(*operator, left, right),
 
                 (Equal, Symbol(left), Symbol(right)) => left == right,
                 (NotEqual, Symbol(left), Symbol(right)) => left != right,
-                (operator, Symbol(_), Symbol(_)) => fail(operator, left, right),
+                (operator, Symbol(_), Symbol(_)) => fail(int, operator, &left, &right),
 
                 (Equal, List(left), List(right)) => left == right,
                 (NotEqual, List(left), List(right)) => left != right,
-                (operator, List(_), List(_)) => fail(operator, left, right),
+                (operator, List(_), List(_)) => fail(int, operator, &left, &right),
 
                 (Equal, Object(left), Object(right)) => left == right,
                 (NotEqual, Object(left), Object(right)) => left != right,
-                (operator, Object(_), List(_)) => fail(operator, left, right),
+                (operator, Object(_), List(_)) => fail(int, operator, &left, &right),
 
                 (Equal, Closure(left), Closure(right)) => left == right,
                 (NotEqual, Closure(left), Closure(right)) => left != right,
-                (operator, Closure(_), Closure(_)) => fail(operator, left, right),
+                (operator, Closure(_), Closure(_)) => fail(int, operator, &left, &right),
 
                 (Equal, Effect(left), Effect(right)) => left == right,
                 (NotEqual, Effect(left), Effect(right)) => left != right,
-                (operator, Effect(_), Effect(_)) => fail(operator, left, right),
+                (operator, Effect(_), Effect(_)) => fail(int, operator, &left, &right),
 
                 (Equal, Intrinsic(left), Intrinsic(right)) => left == right,
                 (NotEqual, Intrinsic(left), Intrinsic(right)) => left != right,
-                (operator, Intrinsic(_), Intrinsic(_)) => fail(operator, left, right),
+                (operator, Intrinsic(_), Intrinsic(_)) => fail(int, operator, &left, &right),
+
+                (Equal, NativeFunction(left), NativeFunction(right)) => left == right,
+                (NotEqual, NativeFunction(left), NativeFunction(right)) => left != right,
+                (operator, NativeFunction(_), NativeFunction(_)) => {
+                    fail(int, operator, &left, &right)
+                }
+
+                (Equal, Task(left), Task(right)) => left == right,
+                (NotEqual, Task(left), Task(right)) => left != right,
+                (operator, Task(_), Task(_)) => fail(int, operator, &left, &right),
+
+                (Equal, Sender(left), Sender(right)) => left == right,
+                (NotEqual, Sender(left), Sender(right)) => left != right,
+                (operator, Sender(_), Sender(_)) => fail(int, operator, &left, &right),
+
+                (Equal, Receiver(left), Receiver(right)) => left == right,
+                (NotEqual, Receiver(left), Receiver(right)) => left != right,
+                (operator, Receiver(_), Receiver(_)) => fail(int, operator, &left, &right),
 
                 // Cover all cases with two different variants.
                 (Equal, Null, _) => false,
                 (NotEqual, Null, _) => true,
-                (operator, Null, _) => fail(operator, left, right),
+                (operator, Null, _) => fail(int, operator, &left, &right),
 
                 (Equal, Bool(_), _) => false,
                 (NotEqual, Bool(_), _) => true,
-                (operator, Bool(_), _) => fail(operator, left, right),
+                (operator, Bool(_), _) => fail(int, operator, &left, &right),
 
                 (Equal, Int(_), _) => false,
                 (NotEqual, Int(_), _) => true,
-                (operator, Int(_), _) => fail(operator, left, right),
+                (operator, Int(_), _) => fail(int, operator, &left, &right),
+
+                (Equal, Float(_), _) => false,
+                (NotEqual, Float(_), _) => true,
+                (operator, Float(_), _) => fail(int, operator, &left, &right),
+
+                (Equal, Str(_), _) => false,
+                (NotEqual, Str(_), _) => true,
+                (operator, Str(_), _) => fail(int, operator, &left, &right),
 
                 (Equal, Symbol(_), _) => false,
                 (NotEqual, Symbol(_), _) => true,
-                (operator, Symbol(_), _) => fail(operator, left, right),
+                (operator, Symbol(_), _) => fail(int, operator, &left, &right),
 
                 (Equal, List(_), _) => false,
                 (NotEqual, List(_), _) => true,
-                (operator, List(_), _) => fail(operator, left, right),
+                (operator, List(_), _) => fail(int, operator, &left, &right),
 
                 (Equal, Object(_), _) => false,
                 (NotEqual, Object(_), _) => true,
-                (operator, Object(_), _) => fail(operator, left, right),
+                (operator, Object(_), _) => fail(int, operator, &left, &right),
 
                 (Equal, Closure(_), _) => false,
                 (NotEqual, Closure(_), _) => true,
-                (operator, Closure(_), _) => fail(operator, left, right),
+                (operator, Closure(_), _) => fail(int, operator, &left, &right),
 
                 (Equal, Effect(_), _) => false,
                 (NotEqual, Effect(_), _) => true,
-                (operator, Effect(_), _) => fail(operator, left, right),
+                (operator, Effect(_), _) => fail(int, operator, &left, &right),
 
                 (Equal, Intrinsic(_), _) => false,
                 (NotEqual, Intrinsic(_), _) => true,
-                (operator, Intrinsic(_), _) => fail(operator, left, right),
+                (operator, Intrinsic(_), _) => fail(int, operator, &left, &right),
+
+                (Equal, NativeFunction(_), _) => false,
+                (NotEqual, NativeFunction(_), _) => true,
+                (operator, NativeFunction(_), _) => fail(int, operator, &left, &right),
+
+                (Equal, Task(_), _) => false,
+                (NotEqual, Task(_), _) => true,
+                (operator, Task(_), _) => fail(int, operator, &left, &right),
+
+                (Equal, Sender(_), _) => false,
+                (NotEqual, Sender(_), _) => true,
+                (operator, Sender(_), _) => fail(int, operator, &left, &right),
+
+                (Equal, Receiver(_), _) => false,
+                (NotEqual, Receiver(_), _) => true,
+                (operator, Receiver(_), _) => fail(int, operator, &left, &right),
             };
             int.push_value(Value::Bool(result));
         })));
@@ -198,14 +305,29 @@ pub struct IfInner {
 impl Eval for IfInner {
     fn eval(mut self: Rc<Self>, int: &mut Interpreter) {
         let value = int.pop_value();
-        let value = match value {
-            Value::Bool(b) => b,
-            _ => panic!("If condition value must be a bool."),
+
+        let if_part = self.ifs.get(self.index).unwrap().clone();
+        let matched = match &if_part.pattern {
+            None => match value {
+                Value::Bool(b) => b,
+                _ => panic!("If condition value must be a bool."),
+            },
+            Some(pattern) => let_pattern_matches(int, pattern, &value),
         };
 
-        let if_part = self.ifs.get(self.index).unwrap();
-        if value {
-            int.push_eval(if_part.body.clone());
+        if matched {
+            if let Some(pattern) = &if_part.pattern {
+                // the bindings of an `if let` are only visible in its body
+                int.push_scope();
+                int.push_eval(Rc::new(PopScope));
+                int.push_eval(if_part.body.clone());
+
+                let mut bindings = PatternBindings::new(false);
+                do_let_pattern_bindings(int, pattern, value, &mut bindings);
+                finish_pattern_bindings(int, bindings);
+            } else {
+                int.push_eval(if_part.body.clone());
+            }
         } else if self.index < self.ifs.len() - 1 {
             Rc::get_mut(&mut self)
                 .expect("Implementation error - can't get IfInner as mut, it is aliased")
@@ -232,7 +354,7 @@ impl Eval for ast::IfExpression {
             else_body: self.else_body.clone(),
         }));
 
-        int.push_eval(self.ifs.get(0).unwrap().cond.clone().into_eval())
+        int.push_eval(self.ifs.first().unwrap().cond.clone().into_eval())
     }
     fn short_name(&self) -> &str {
         "If"
@@ -287,6 +409,239 @@ impl Eval for ast::LoopExpression {
     }
 }
 
+// Decides what a for loop iterates over, once the expression after `in` has produced a value.
+// Generators are already sending yield effects, lists are iterated natively, and objects
+// can implement the iter symbol.
+#[derive(Debug)]
+pub struct ForIterate;
+impl Eval for ForIterate {
+    fn eval(self: Rc<Self>, int: &mut Interpreter) {
+        let value = int.pop_value();
+        match value {
+            Value::Effect(_) => int.push_value(value),
+            Value::List(list) => {
+                // use a fresh scope so that the loop doesn't alias the current one
+                let scope = Rc::new(Scope::with_bindings(None, HashMap::new()));
+                int.push_fn_context(FunctionContext::new(scope));
+                int.push_eval(Rc::new(ListIter { list, index: 0 }));
+            }
+            Value::Receiver(channel) => {
+                let scope = Rc::new(Scope::with_bindings(None, HashMap::new()));
+                int.push_fn_context(FunctionContext::new(scope));
+                int.push_eval(Rc::new(ChannelIter { channel }));
+            }
+            _ => match value.well_known_method(WellKnownSymbol::Iter) {
+                Some(method) => call_value(int, method, vec![value]),
+                // anything else is the result of the loop
+                None => int.push_value(value),
+            },
+        }
+    }
+    fn short_name(&self) -> &str {
+        "ForIterate"
+    }
+}
+
+// Native generator which yields each element of a list.
+#[derive(Debug)]
+pub struct ListIter {
+    list: Rc<Vec<Value>>,
+    index: usize,
+}
+impl Eval for ListIter {
+    fn eval(self: Rc<Self>, int: &mut Interpreter) {
+        match self.list.get(self.index) {
+            Some(value) => {
+                int.push_eval(Rc::new(ListIter {
+                    list: self.list.clone(),
+                    index: self.index + 1,
+                }));
+                int.push_eval(Rc::new(Custom::new("IgnoreValue", |int| {
+                    int.pop_value();
+                })));
+                int.push_eval(Rc::new(SendInner));
+                int.push_value(value.clone());
+                int.push_value(Value::Symbol(WellKnownSymbol::Yield.id()));
+            }
+            None => int.push_value(Value::Null),
+        }
+    }
+    fn short_name(&self) -> &str {
+        "ListIter"
+    }
+}
+
+// Native generator which yields each value received from a channel, until it is closed.
+#[derive(Debug)]
+pub struct ChannelIter {
+    channel: ChannelId,
+}
+impl Eval for ChannelIter {
+    fn eval(self: Rc<Self>, int: &mut Interpreter) {
+        let channel = self.channel;
+        int.push_eval(Rc::new(Custom::new(
+            "ChannelIterNext",
+            move |int| match int.pop_value() {
+                Value::List(received) => {
+                    int.push_eval(Rc::new(ChannelIter { channel }));
+                    int.push_eval(Rc::new(Custom::new("IgnoreValue", |int| {
+                        int.pop_value();
+                    })));
+                    int.push_eval(Rc::new(SendInner));
+                    int.push_value(received[0].clone());
+                    int.push_value(Value::Symbol(WellKnownSymbol::Yield.id()));
+                }
+                // the channel is closed
+                _ => int.push_value(Value::Null),
+            },
+        )));
+        int.wait_task(Wait::Next(channel));
+    }
+    fn short_name(&self) -> &str {
+        "ChannelIter"
+    }
+}
+
+impl Eval for ast::ForExpression {
+    fn eval(self: Rc<Self>, int: &mut Interpreter) {
+        // the body is a yield handler which continues with the value of the body
+        int.push_eval(Rc::new(Handler {
+            match_arms: vec![(
+                WellKnownSymbol::Yield.id(),
+                self.pattern.clone(),
+                self.block.clone(),
+            )],
+        }));
+        int.push_eval(Rc::new(ForIterate));
+        int.push_eval(self.expr.clone().into_eval());
+    }
+    fn short_name(&self) -> &str {
+        "For"
+    }
+}
+
+// Tries the arms of a match in order, starting from `index`, against the value on the stack.
+#[derive(Debug)]
+pub struct MatchInner {
+    match_expr: Rc<ast::MatchExpression>,
+    index: usize,
+}
+impl Eval for MatchInner {
+    fn eval(self: Rc<Self>, int: &mut Interpreter) {
+        let value = int.pop_value();
+
+        for (index, arm) in self.match_expr.arms.iter().enumerate().skip(self.index) {
+            if !subpattern_matches(int, &arm.pattern, &value) {
+                continue;
+            }
+
+            // the bindings of an arm are only visible to its guard and body
+            int.push_scope();
+            match &arm.guard {
+                None => {
+                    int.push_eval(Rc::new(PopScope));
+                    int.push_eval(arm.body.clone());
+                }
+                Some(guard) => {
+                    int.push_eval(Rc::new(MatchGuard {
+                        match_expr: self.match_expr.clone(),
+                        index,
+                        value: value.clone(),
+                    }));
+                    int.push_eval(guard.clone().into_eval());
+                }
+            }
+
+            let mut bindings = PatternBindings::new(false);
+            do_subpattern_binding(int, &arm.pattern, value, &mut bindings);
+            finish_pattern_bindings(int, bindings);
+            return;
+        }
+
+        let message = format!(
+            "No arm of the match expression matched {}.",
+            int.display_value(&value)
+        );
+        send_error(int, "no_match", message, value);
+    }
+    fn short_name(&self) -> &str {
+        "MatchInner"
+    }
+}
+
+// Runs the body of a match arm if its guard passed, otherwise tries the next arm.
+#[derive(Debug)]
+pub struct MatchGuard {
+    match_expr: Rc<ast::MatchExpression>,
+    index: usize,
+    value: Value,
+}
+impl Eval for MatchGuard {
+    fn eval(self: Rc<Self>, int: &mut Interpreter) {
+        let passed = match int.pop_value() {
+            Value::Bool(b) => b,
+            _ => panic!("Match guard value must be a bool."),
+        };
+
+        if passed {
+            int.push_eval(Rc::new(PopScope));
+            int.push_eval(self.match_expr.arms[self.index].body.clone());
+        } else {
+            int.pop_scope();
+            int.push_eval(Rc::new(MatchInner {
+                match_expr: self.match_expr.clone(),
+                index: self.index + 1,
+            }));
+            int.push_value(self.value.clone());
+        }
+    }
+    fn short_name(&self) -> &str {
+        "MatchGuard"
+    }
+}
+
+impl Eval for ast::MatchExpression {
+    fn eval(self: Rc<Self>, int: &mut Interpreter) {
+        int.push_eval(Rc::new(MatchInner {
+            match_expr: self.clone(),
+            index: 0,
+        }));
+        int.push_eval(self.expr.clone().into_eval());
+    }
+    fn short_name(&self) -> &str {
+        "Match"
+    }
+}
+
+// Raise an error which Kal code can catch by handling the `error` effect. The payload
+// is an object like { kind: "no_match", message: "...", value: ... }.
+pub fn send_error(int: &mut Interpreter, kind: &str, message: String, value: Value) {
+    let mut payload = HashMap::new();
+    payload.insert(
+        Key::Str("kind".to_owned()),
+        Value::Str(Rc::new(kind.to_owned())),
+    );
+    payload.insert(Key::Str("message".to_owned()), Value::Str(Rc::new(message)));
+    payload.insert(Key::Str("value".to_owned()), value);
+
+    int.push_value(Value::Object(Rc::new(payload)));
+    int.push_value(Value::Symbol(WellKnownSymbol::Error.id()));
+    int.push_eval(Rc::new(SendInner));
+}
+
+// Send an error returned by a native function or intrinsic. It is sent from a function context of
+// its own, so the error is the value of the call, like an error sent from inside a closure.
+pub fn send_call_error(int: &mut Interpreter, err: crate::Error) {
+    let scope = int.current_scope().clone();
+    int.push_fn_context(FunctionContext::new(scope));
+    send_runtime_error(int, err);
+}
+
+pub fn send_runtime_error(int: &mut Interpreter, err: crate::Error) {
+    let (kind, message, value) = err.into_parts();
+    send_error(int, &kind, message, value);
+}
+
 impl Eval for ast::IndexExpression {
     fn eval(self: Rc<Self>, int: &mut Interpreter) {
         int.push_eval(Rc::new(Custom::new("IndexInner", |int| {
@@ -340,6 +695,9 @@ impl Eval for ast::List {
                     }
                 }
             }
+            if let Err(err) = int.allocate(list_bytes(list.len())) {
+                return send_runtime_error(int, err);
+            }
             int.push_value(Value::List(Rc::new(list)))
         })));
 
@@ -361,7 +719,7 @@ impl Eval for ast::Assignment {
         int.push_eval(Rc::new(Custom::new("AssignmentInner", move |int| {
             let value = int.pop_value();
 
-            *int.resolve_location_chain_mut(&self2.location) = value;
+            int.assign_location_chain(&self2.location, value);
         })));
 
         int.push_eval(self.expr.clone().into_eval());
@@ -418,6 +776,15 @@ impl Eval for ast::Int {
     }
 }
 
+impl Eval for ast::Str {
+    fn eval(self: Rc<Self>, int: &mut Interpreter) {
+        int.push_value(Value::Str(Rc::new(self.0.clone())))
+    }
+    fn short_name(&self) -> &str {
+        "Str"
+    }
+}
+
 impl Eval for ast::Function {
     fn eval(self: Rc<Self>, int: &mut Interpreter) {
         let scope = int.branch_scope();
@@ -432,8 +799,9 @@ impl Eval for ast::Function {
 impl Eval for ast::NamedFunction {
     fn eval(self: Rc<Self>, int: &mut Interpreter) {
         let scope = int.branch_scope();
-        let value = Value::Closure(Rc::new(Closure::new(self.function.clone(), scope)));
-        int.create_binding(self.name.clone(), value);
+        let mut closure = Closure::new(self.function.clone(), scope);
+        closure.name = Some(self.name.clone());
+        int.create_binding(self.name.clone(), Value::Closure(Rc::new(closure)));
     }
     fn short_name(&self) -> &str {
         "NamedFunction"
@@ -494,11 +862,77 @@ impl Eval for ast::ExpressionStatement {
     }
 }
 
-fn do_object_pattern_bindings(int: &mut Interpreter, pattern: &ast::ObjectPattern, vals: Value) {
+// The state of binding a pattern.
+struct PatternBindings {
+    // whether the pattern was declared with `let mut`
+    mutable: bool,
+    // Defaults needed by the pattern. They have to be evaluated on the eval stack
+    // before they can be bound, so they are collected while binding and pushed afterwards.
+    defaults: Vec<(Rc<ast::ListSubPattern>, Rc<dyn ast::Expression>)>,
+    // the size of the lists and objects made by spreads in the pattern
+    allocated: usize,
+}
+
+impl PatternBindings {
+    fn new(mutable: bool) -> Self {
+        PatternBindings {
+            mutable,
+            defaults: Vec::new(),
+            allocated: 0,
+        }
+    }
+
+    fn create_binding(&self, int: &mut Interpreter, name: String, value: Value) {
+        if self.mutable {
+            int.create_mutable_binding(name, value);
+        } else {
+            int.create_binding(name, value);
+        }
+    }
+}
+
+// Check the lists and objects that the pattern made fit in the heap limit, and push the
+// defaults it needs.
+fn finish_pattern_bindings(int: &mut Interpreter, bindings: PatternBindings) {
+    if let Err(err) = int.allocate(bindings.allocated) {
+        return send_runtime_error(int, err);
+    }
+    // pushed in reverse so that the defaults are evaluated left to right
+    for (pattern, default) in bindings.defaults.into_iter().rev() {
+        int.push_eval(Rc::new(PatternDefaultInner {
+            pattern,
+            mutable: bindings.mutable,
+        }));
+        int.push_eval(default.into_eval());
+    }
+}
+
+#[derive(Debug)]
+pub struct PatternDefaultInner {
+    pattern: Rc<ast::ListSubPattern>,
+    mutable: bool,
+}
+impl Eval for PatternDefaultInner {
+    fn eval(self: Rc<Self>, int: &mut Interpreter) {
+        let val = int.pop_value();
+        let mut bindings = PatternBindings::new(self.mutable);
+        do_subpattern_binding(int, &self.pattern, val, &mut bindings);
+        finish_pattern_bindings(int, bindings);
+    }
+    fn short_name(&self) -> &str {
+        "PatternDefaultInner"
+    }
+}
+
+fn do_object_pattern_bindings(
+    int: &mut Interpreter,
+    pattern: &ast::ObjectPattern,
+    vals: Value,
+    bindings: &mut PatternBindings,
+) {
     let mut vals = match vals {
-        Value::Object(hm) => Rc::try_unwrap(hm).expect(
-            "Can't destructure object into object pattern. The value has another reference.",
-        ),
+        // take the object if we own it, otherwise destructure a copy
+        Value::Object(hm) => Rc::unwrap_or_clone(hm),
         _ => panic!("Can't match object pattern. The value to destructure was not an object."),
     };
 
@@ -511,7 +945,7 @@ fn do_object_pattern_bindings(int: &mut Interpreter, pattern: &ast::ObjectPatter
                         "Could not bind {} in the patttern, did not receive it from the object.",
                         name
                     ),
-                    Some(v) => int.create_binding(name.to_owned(), v),
+                    Some(v) => bindings.create_binding(int, name.to_owned(), v),
                 }
             }
             ast::ObjectSubPattern::List(name, pattern) => {
@@ -519,7 +953,7 @@ fn do_object_pattern_bindings(int: &mut Interpreter, pattern: &ast::ObjectPatter
                     None => panic!("Could not navigate {} in the patttern, did not receive it from the object.", name),
                     Some(vals) => {
 
-                        do_list_pattern_bindings(int, pattern, vals);
+                        do_list_pattern_bindings(int, pattern, vals, bindings);
                     }
                 }
             }
@@ -528,138 +962,390 @@ fn do_object_pattern_bindings(int: &mut Interpreter, pattern: &ast::ObjectPatter
                     None => panic!("Could not navigate {} in the patttern, did not receive it from the object.", name),
                     Some(vals) => {
 
-                        do_object_pattern_bindings(int, pattern, vals);
+                        do_object_pattern_bindings(int, pattern, vals, bindings);
                     }
                 }
             }
+            ast::ObjectSubPattern::Keyed(key, pattern) => {
+                let key = resolve_pattern_key(int, key);
+                let val = vals.remove(&key);
+                if !do_optional_subpattern_binding(int, pattern, val, bindings) {
+                    panic!(
+                        "Could not bind {} in the pattern, did not receive it from the object.",
+                        int.symbols().display_key(&key)
+                    );
+                }
+            }
         }
     }
 
     match &pattern.final_pattern {
-        None => {},
-        Some(ast::ObjectFinalPattern::SpreadNameless) => {},
-        Some(ast::ObjectFinalPattern::Spread(name)) => int.create_binding(name.clone(), Value::Object(Rc::new(vals))),
+        None => {}
+        Some(ast::ObjectFinalPattern::SpreadNameless) => {}
+        Some(ast::ObjectFinalPattern::Spread(name)) => {
+            bindings.allocated += object_bytes(vals.len());
+            bindings.create_binding(int, name.clone(), Value::Object(Rc::new(vals)))
+        }
         Some(ast::ObjectFinalPattern::Wildcard) => {
             for (key, val) in vals.into_iter() {
                 match key {
-                    Key::Str(s) => int.create_binding(s, val),
-                    Key::Null => {},
-                    Key::Bool(_) => {},
-                    Key::Int(_) => {},
-                    Key::Symbol(_) => {},   
+                    Key::Str(s) => bindings.create_binding(int, s, val),
+                    Key::Null => {}
+                    Key::Bool(_) => {}
+                    Key::Int(_) => {}
+                    Key::Symbol(_) => {}
                 }
             }
+        }
+    }
+}
+
+fn resolve_pattern_key(int: &mut Interpreter, key: &ast::PatternKey) -> Key {
+    match key {
+        ast::PatternKey::Null => Key::Null,
+        ast::PatternKey::Bool(b) => Key::Bool(*b),
+        ast::PatternKey::Int(i) => Key::Int(*i),
+        ast::PatternKey::Str(s) => Key::Str(s.clone()),
+        ast::PatternKey::Ident(name) => match int.current_scope().resolve_binding(name) {
+            Some(value) => Key::from_value(&value),
+            None => panic!(
+                "Could not resolve name {:?} used as a key in an object pattern.",
+                name
+            ),
+        },
+    }
+}
+
+fn do_subpattern_binding(
+    int: &mut Interpreter,
+    pattern: &ast::ListSubPattern,
+    val: Value,
+    bindings: &mut PatternBindings,
+) {
+    match pattern {
+        ast::ListSubPattern::Ident(name) => bindings.create_binding(int, name.to_owned(), val),
+        ast::ListSubPattern::List(pattern) => do_list_pattern_bindings(int, pattern, val, bindings),
+        ast::ListSubPattern::Object(pattern) => {
+            do_object_pattern_bindings(int, pattern, val, bindings)
+        }
+        ast::ListSubPattern::Default(pattern, _default) => {
+            do_subpattern_binding(int, pattern, val, bindings)
+        }
+        ast::ListSubPattern::Literal(literal) => {
+            if !subpattern_matches(int, pattern, &val) {
+                panic!(
+                    "Could not match {} against the literal {:?} in the pattern.",
+                    int.display_nested(&val),
+                    literal
+                );
+            }
+        }
+    }
+}
+
+// Bind a sub-pattern to a value that might be missing, in which case the pattern's
+// default is used. Returns false if there is no value and no default.
+fn do_optional_subpattern_binding(
+    int: &mut Interpreter,
+    pattern: &ast::ListSubPattern,
+    val: Option<Value>,
+    bindings: &mut PatternBindings,
+) -> bool {
+    match (pattern, val) {
+        (_, Some(val)) => do_subpattern_binding(int, pattern, val, bindings),
+        (ast::ListSubPattern::Default(pattern, default), None) => {
+            bindings.defaults.push((pattern.clone(), default.clone()))
+        }
+        (_, None) => return false,
+    }
+    true
+}
+
+fn num_required_patterns(patterns: &[ast::ListSubPattern]) -> usize {
+    patterns
+        .iter()
+        .filter(|p| !matches!(p, ast::ListSubPattern::Default(..)))
+        .count()
+}
+
+// Share out the values of a list between the patterns before a spread, the spread, and the
+// patterns after it. Values go to the patterns after the spread first, then to the patterns
+// before it, and whatever is left over goes into the spread. Patterns that miss out use their
+// defaults. Returns None if there aren't enough values for the patterns without defaults.
+fn spread_allocation(
+    before_patterns: &[ast::ListSubPattern],
+    after_patterns: &[ast::ListSubPattern],
+    n_vals: usize,
+) -> Option<(usize, usize, usize)> {
+    let n_required_before = num_required_patterns(before_patterns);
+    if n_vals < n_required_before + num_required_patterns(after_patterns) {
+        return None;
+    }
+    let n_vals_after = after_patterns.len().min(n_vals - n_required_before);
+    let n_vals_before = before_patterns.len().min(n_vals - n_vals_after);
+    Some((
+        n_vals_before,
+        n_vals - n_vals_before - n_vals_after,
+        n_vals_after,
+    ))
+}
+
+// Test whether a value fits a pattern without binding anything, so that refutable patterns can
+// be tried before committing to one. Defaults aren't evaluated, so a sub-pattern with a
+// default always matches a missing value.
+fn subpattern_matches(int: &mut Interpreter, pattern: &ast::ListSubPattern, val: &Value) -> bool {
+    match pattern {
+        ast::ListSubPattern::Ident(_) => true,
+        ast::ListSubPattern::List(pattern) => match val {
+            Value::List(vals) => list_pattern_matches(int, pattern, vals),
+            _ => false,
+        },
+        ast::ListSubPattern::Object(pattern) => match val {
+            Value::Object(vals) => object_pattern_matches(int, pattern, vals),
+            _ => false,
         },
+        ast::ListSubPattern::Default(pattern, _default) => subpattern_matches(int, pattern, val),
+        ast::ListSubPattern::Literal(literal) => {
+            Key::try_from_value(val) == Some(resolve_pattern_key(int, literal))
+        }
+    }
+}
+
+fn list_pattern_matches(int: &mut Interpreter, pattern: &ast::ListPattern, vals: &[Value]) -> bool {
+    let before_patterns = &pattern.before_patterns;
+    match &pattern.spread_and_after_patterns {
+        None => {
+            (num_required_patterns(before_patterns)..=before_patterns.len()).contains(&vals.len())
+                && before_patterns
+                    .iter()
+                    .zip(vals)
+                    .all(|(p, v)| subpattern_matches(int, p, v))
+        }
+        Some((_spread, after_patterns)) => {
+            match spread_allocation(before_patterns, after_patterns, vals.len()) {
+                None => false,
+                Some((n_vals_before, _n_vals_into_spread, n_vals_after)) => {
+                    before_patterns
+                        .iter()
+                        .zip(&vals[..n_vals_before])
+                        .all(|(p, v)| subpattern_matches(int, p, v))
+                        && after_patterns
+                            .iter()
+                            .zip(&vals[vals.len() - n_vals_after..])
+                            .all(|(p, v)| subpattern_matches(int, p, v))
+                }
+            }
+        }
     }
 }
 
-fn do_list_pattern_bindings(int: &mut Interpreter, pattern: &ast::ListPattern, vals: Value) {
+fn object_pattern_matches(
+    int: &mut Interpreter,
+    pattern: &ast::ObjectPattern,
+    vals: &HashMap<Key, Value>,
+) -> bool {
+    pattern.patterns.iter().all(|p| match p {
+        ast::ObjectSubPattern::Ident(name) => vals.contains_key(&Key::Str(name.to_string())),
+        ast::ObjectSubPattern::List(name, pattern) => match vals.get(&Key::Str(name.to_string())) {
+            Some(Value::List(vals)) => list_pattern_matches(int, pattern, vals),
+            _ => false,
+        },
+        ast::ObjectSubPattern::Object(name, pattern) => match vals.get(&Key::Str(name.to_string()))
+        {
+            Some(Value::Object(vals)) => object_pattern_matches(int, pattern, vals),
+            _ => false,
+        },
+        ast::ObjectSubPattern::Keyed(key, pattern) => {
+            let key = resolve_pattern_key(int, key);
+            match vals.get(&key) {
+                Some(val) => subpattern_matches(int, pattern, val),
+                None => matches!(pattern, ast::ListSubPattern::Default(..)),
+            }
+        }
+    })
+}
+
+fn do_list_pattern_bindings(
+    int: &mut Interpreter,
+    pattern: &ast::ListPattern,
+    vals: Value,
+    bindings: &mut PatternBindings,
+) {
     let vals = match vals {
-        Value::List(l) => Rc::try_unwrap(l).expect(
-            "Can't destructure list value into list pattern. The value has another reference.",
-        ),
+        Value::List(l) => Rc::unwrap_or_clone(l),
         _ => panic!("Can't match list pattern. The value to destructure was not a list."),
     };
-    do_list_pattern_bindings_no_unwrap(int, pattern, vals);
+    do_list_pattern_bindings_no_unwrap(int, pattern, vals, bindings);
 }
 
-fn do_list_pattern_bindings_no_unwrap(int: &mut Interpreter, pattern: &ast::ListPattern, vals: Vec<Value>) {
-
+fn do_list_pattern_bindings_no_unwrap(
+    int: &mut Interpreter,
+    pattern: &ast::ListPattern,
+    vals: Vec<Value>,
+    bindings: &mut PatternBindings,
+) {
     let n_vals_provided = vals.len();
 
-    let mut vals = vals.into_iter().peekable();
+    let mut vals = vals.into_iter();
 
-    fn bind_subpattern(int: &mut Interpreter, pattern: &ast::ListSubPattern, vals: &mut Peekable<IntoIter<Value>>) {
+    fn bind_subpattern(
+        int: &mut Interpreter,
+        pattern: &ast::ListSubPattern,
+        val: Option<Value>,
+        bindings: &mut PatternBindings,
+    ) {
+        if do_optional_subpattern_binding(int, pattern, val, bindings) {
+            return;
+        }
         match pattern {
-            ast::ListSubPattern::Ident(name) => {
-                // todo: conversion method for &String to &Key::Str(String), maybe some kind of deref impl?
-                match vals.next() {
-                    None => panic!("Could not bind {} in the pattern, not enough values provided to unpack list pattern.", name),
-                    Some(v) => int.create_binding(name.to_owned(), v),
-                }
+            ast::ListSubPattern::Ident(name) => panic!("Could not bind {} in the pattern, not enough values provided to unpack list pattern.", name),
+            ast::ListSubPattern::List(_) => panic!(
+                "Could not destructure list in list pattern, not enough values provided."
+            ),
+            ast::ListSubPattern::Object(_) => panic!(
+                "Could not destructure object in list pattern, not enough values provided."
+            ),
+            ast::ListSubPattern::Literal(literal) => panic!(
+                "Could not match the literal {:?} in list pattern, not enough values provided.",
+                literal
+            ),
+            ast::ListSubPattern::Default(..) => unreachable!("Patterns with defaults can always be bound."),
+        }
+    }
+
+    match &pattern.spread_and_after_patterns {
+        None => {
+            for pattern in &pattern.before_patterns {
+                bind_subpattern(int, pattern, vals.next(), bindings);
+            }
+        }
+        Some((spread, after_params)) => {
+            let (n_vals_before, n_vals_into_spread, _n_vals_after) =
+                spread_allocation(&pattern.before_patterns, after_params, n_vals_provided).unwrap_or_else(|| {
+                    let n_required = num_required_patterns(&pattern.before_patterns) + num_required_patterns(after_params);
+                    panic!("Not enough params remaining for patterns after the spread. Expected at least {} values total, but got {}", n_required, n_vals_provided);
+                });
+
+            let mut before_vals = vals.by_ref().take(n_vals_before);
+            for pattern in &pattern.before_patterns {
+                bind_subpattern(int, pattern, before_vals.next(), bindings);
+            }
+
+            let spread_values: Vec<Value> = vals.by_ref().take(n_vals_into_spread).collect();
+            if let ast::SpreadPattern::Named(name) = spread {
+                bindings.allocated += list_bytes(spread_values.len());
+                bindings.create_binding(int, name.clone(), Value::List(Rc::new(spread_values)));
+            }
+
+            for pattern in after_params {
+                bind_subpattern(int, pattern, vals.next(), bindings);
             }
-            ast::ListSubPattern::List(pattern) => match vals.next() {
-                None => panic!(
-                    "Could not destructure list in list pattern, not enough values provided."
-                ),
-                Some(vals) => {
-                    do_list_pattern_bindings(int, pattern, vals);
-                }
-            },
-            ast::ListSubPattern::Object(pattern) => match vals.next() {
-                None => panic!(
-                    "Could not destructure object in list pattern, not enough values provided."
-                ),
-                Some(vals) => {
-                    do_object_pattern_bindings(int, pattern, vals);
-                }
-            },
         }
     }
 
-    for pattern in &pattern.before_patterns {
-        bind_subpattern(int, pattern, &mut vals);
+    if vals.next().is_some() {
+        panic!("Too many params provided to list pattern.");
     }
+}
 
-    if let Some((spread, after_params)) = &pattern.spread_and_after_patterns {
-        let n_vals_into_spread = n_vals_provided as i64
-            - (pattern.before_patterns.len() as i64 + after_params.len() as i64);
-        if n_vals_into_spread < 0 {
-            panic!("Not enough params remaining for patterns after the spread. Expected at least {} values total, but got {}", pattern.before_patterns.len() + after_params.len(), n_vals_provided);
+#[derive(Debug)]
+pub struct LetInner {
+    pattern: Rc<ast::LetPattern>,
+    mutable: bool,
+}
+impl Eval for LetInner {
+    fn eval(self: Rc<Self>, int: &mut Interpreter) {
+        let val = int.pop_value();
+
+        let mut bindings = PatternBindings::new(self.mutable);
+        do_let_pattern_bindings(int, &self.pattern, val, &mut bindings);
+        finish_pattern_bindings(int, bindings);
+    }
+    fn short_name(&self) -> &str {
+        "LetInner"
+    }
+}
+
+fn do_let_pattern_bindings(
+    int: &mut Interpreter,
+    pattern: &ast::LetPattern,
+    val: Value,
+    bindings: &mut PatternBindings,
+) {
+    use ast::LetPattern::*;
+    match pattern {
+        Ident(name) => {
+            bindings.create_binding(int, name.clone(), name_closure(val, name));
         }
-        let mut spread_values = Vec::new();
-        for _ in 0..n_vals_into_spread {
-            match vals.next() {
-                Some(val) => spread_values.push(val),
-                None => panic!("Shouldn't happen. Tried to unpack too many values into spread."),
-            }
+        List(pattern) => {
+            do_list_pattern_bindings(int, pattern, val, bindings);
         }
-        if let ast::SpreadPattern::Named(name) = spread {
-            int.create_binding(name.clone(), Value::List(Rc::new(spread_values)));
+        Object(pattern) => {
+            do_object_pattern_bindings(int, pattern, val, bindings);
         }
+    }
+}
 
-        for pattern in after_params {
-            bind_subpattern(int, pattern, &mut vals);
+// Closures take the name of the first binding they are assigned to, so that errors can mention it.
+fn name_closure(mut val: Value, name: &str) -> Value {
+    if let Value::Closure(closure) = &mut val {
+        if let Some(closure) = Rc::get_mut(closure) {
+            closure.name.get_or_insert_with(|| name.to_owned());
         }
     }
+    val
+}
 
-    match vals.next() {
-        Some(_) => panic!("Too many params provided to list pattern."),
-        None => {}
+fn let_pattern_matches(int: &mut Interpreter, pattern: &ast::LetPattern, val: &Value) -> bool {
+    use ast::LetPattern::*;
+    match (pattern, val) {
+        (Ident(_), _) => true,
+        (List(pattern), Value::List(vals)) => list_pattern_matches(int, pattern, vals),
+        (Object(pattern), Value::Object(vals)) => object_pattern_matches(int, pattern, vals),
+        _ => false,
     }
 }
 
+// `let ... else`, which runs the else block instead of binding when the pattern doesn't match.
 #[derive(Debug)]
-pub struct LetInner {
+pub struct LetElseInner {
     pattern: Rc<ast::LetPattern>,
+    mutable: bool,
+    else_body: Rc<ast::Block>,
 }
-impl Eval for LetInner {
+impl Eval for LetElseInner {
     fn eval(self: Rc<Self>, int: &mut Interpreter) {
         let val = int.pop_value();
 
-        use ast::LetPattern::*;
-        match &*self.pattern {
-            Ident(name) => {
-                int.create_binding(name.clone(), val);
-            }
-            List(pattern) => {
-                do_list_pattern_bindings(int, pattern, val);
-            }
-            Object(pattern) => {
-                do_object_pattern_bindings(int, pattern, val);
-            }
+        if let_pattern_matches(int, &self.pattern, &val) {
+            let mut bindings = PatternBindings::new(self.mutable);
+            do_let_pattern_bindings(int, &self.pattern, val, &mut bindings);
+            finish_pattern_bindings(int, bindings);
+        } else {
+            int.push_eval(Rc::new(Custom::new("LetElseFinished", |_int| {
+                panic!("The else block of a let-else must not finish normally. It should return, break or continue.");
+            })));
+            int.push_eval(self.else_body.clone());
         }
     }
     fn short_name(&self) -> &str {
-        "LetInner"
+        "LetElseInner"
     }
 }
 
 impl Eval for ast::LetStatement {
     fn eval(self: Rc<Self>, int: &mut Interpreter) {
-        int.push_eval(Rc::new(LetInner {
-            pattern: self.pattern.clone(),
-        }));
+        match &self.else_body {
+            None => int.push_eval(Rc::new(LetInner {
+                pattern: self.pattern.clone(),
+                mutable: self.mutable,
+            })),
+            Some(else_body) => int.push_eval(Rc::new(LetElseInner {
+                pattern: self.pattern.clone(),
+                mutable: self.mutable,
+                else_body: else_body.clone(),
+            })),
+        }
         int.push_eval(self.expr.clone().into_eval());
     }
     fn short_name(&self) -> &str {
@@ -674,22 +1360,49 @@ impl Eval for ast::NumericExpression {
             let left = int.pop_value();
             let right = int.pop_value();
 
-            let left = match left {
-                Value::Int(i) => i,
-                _ => panic!("Cant add, left side not an Int."),
+            use ast::NumericOperator::*;
+            // objects can overload numeric operators with the corresponding symbol
+            let symbol = match operator {
+                Add => WellKnownSymbol::Add,
+                Multiply => WellKnownSymbol::Multiply,
+                Subtract => WellKnownSymbol::Subtract,
+                Divide => WellKnownSymbol::Divide,
             };
-            let right = match right {
-                Value::Int(i) => i,
-                _ => panic!("Cant add, right side not an Int."),
+            if let Some(method) = left
+                .well_known_method(symbol)
+                .or_else(|| right.well_known_method(symbol))
+            {
+                call_value(int, method, vec![left, right]);
+                return;
+            }
+
+            // ints stay ints, unless either side is a float
+            let (left, right) = match (left, right) {
+                (Value::Int(left), Value::Int(right)) => {
+                    let val = match operator {
+                        Add => left + right,
+                        Multiply => left * right,
+                        Subtract => left - right,
+                        Divide => left / right,
+                    };
+                    int.push_value(Value::Int(val));
+                    return;
+                }
+                (Value::Int(left), Value::Float(right)) => (left as f64, right),
+                (Value::Float(left), Value::Int(right)) => (left, right as f64),
+                (Value::Float(left), Value::Float(right)) => (left, right),
+                (Value::Int(_) | Value::Float(_), _) => {
+                    panic!("Cant add, right side not a number.")
+                }
+                _ => panic!("Cant add, left side not a number."),
             };
-            use ast::NumericOperator::*;
             let val = match operator {
-                Add => Value::Int(left + right),
-                Multiply => Value::Int(left * right),
-                Subtract => Value::Int(left - right),
-                Divide => Value::Int(left / right),
+                Add => left + right,
+                Multiply => left * right,
+                Subtract => left - right,
+                Divide => left / right,
             };
-            int.push_value(val)
+            int.push_value(Value::Float(val))
         })));
         int.push_eval(self.left.clone().into_eval());
         int.push_eval(self.right.clone().into_eval());
@@ -744,9 +1457,10 @@ impl Eval for ast::NegativeExpression {
             let val = int.pop_value();
             let val = match val {
                 Value::Int(i) => i,
-                _ => panic!("Cant negate, val not an Int."),
+                Value::Float(f) => return int.push_value(Value::Float(-f)),
+                _ => panic!("Cant negate, val not a number."),
             };
-            if val == std::i64::MIN {
+            if val == i64::MIN {
                 // TODO: BigInteger wrapping
                 panic!("Can't negate i64::min.");
             }
@@ -762,11 +1476,10 @@ impl Eval for ast::NegativeExpression {
 
 impl Eval for String {
     fn eval(self: Rc<Self>, int: &mut Interpreter) {
-        let val_ref = match int.current_scope().resolve_binding(self.as_str()) {
-            Some(val_ref) => val_ref,
+        let value = match int.current_scope().resolve_binding(self.as_str()) {
+            Some(value) => value,
             None => panic!("Could not resolve name {:?}", self.as_str()),
         };
-        let value = val_ref.clone();
         int.push_value(value);
     }
     fn short_name(&self) -> &str {
@@ -774,126 +1487,336 @@ impl Eval for String {
     }
 }
 
-impl Eval for ast::FunctionInvocation {
-    fn eval(self: Rc<Self>, int: &mut Interpreter) {
-        let num_params_provided = self.elems.len();
-        let self2 = self.clone();
-        int.push_eval(Rc::new(Custom::new(
-            "FunctionInvocationInner",
-            move |int| {
-                let callable = int.pop_value();
+// Call a closure, intrinsic or callable object with arguments that have already been evaluated.
+// The return value ends up on the value stack of the current context.
+pub fn call_value(int: &mut Interpreter, callable: Value, values: Vec<Value>) {
+    call_value_with_keywords(int, callable, values, HashMap::new());
+}
 
-                match &callable {
-                    Value::Closure(_) => {}
-                    Value::Intrinsic(_) => {}
-                    _ => panic!("Cannot call value other than closure."),
-                };
+pub fn call_value_with_keywords(
+    int: &mut Interpreter,
+    callable: Value,
+    values: Vec<Value>,
+    keywords: HashMap<Key, Value>,
+) {
+    let num_params_provided = values.len();
+
+    match callable {
+        Value::NativeFunction(native) => {
+            assert!(
+                keywords.is_empty(),
+                "{} doesn't take keyword arguments.",
+                native.name,
+            );
+            let result = native
+                .call(int, &values)
+                .and_then(|value| int.allocate(value_bytes(&value)).map(|_| value));
+            match result {
+                Ok(value) => int.push_value(value),
+                Err(err) => send_call_error(int, err),
+            }
+        }
+        Value::Intrinsic(intrinsic) => {
+            assert!(
+                keywords.is_empty(),
+                "{} doesn't take keyword arguments.",
+                intrinsic.name(),
+            );
+
+            // intrinsic needs values back on the stack instead of as bindings
+            // todo: we can avoid both taking off and putting back on the stack by checking if there is a spread in the function invocation
+            for value in values {
+                int.push_value(value);
+            }
 
-                // spreads mean this capacity isn't actually correct
-                let mut values = Vec::with_capacity(num_params_provided);
-                for elem in &self2.elems {
-                    match elem {
-                        ast::ListElem::Spread(_) => {
-                            let list = int.pop_value();
-                            let list = match list {
-                                Value::List(l) => Rc::try_unwrap(l).expect(
-                                    "Shouldn't happen. Newly evaluated value has a reference.",
-                                ),
-                                _ => {
-                                    panic!("Interpreter error. Expected a list on the eval stack.")
-                                }
-                            };
-                            for val in list {
-                                values.push(val);
-                            }
-                        }
-                        ast::ListElem::Elem(_) => {
-                            values.push(int.pop_value());
-                        }
-                    };
-                }
-                let num_params_provided = values.len();
-
-                match callable {
-                    Value::Intrinsic(intrinsic) => {
-                        // intrinsic needs values back on the stack instead of as bindings
-                        // todo: we can avoid both taking off and putting back on the stack by checking if there is a spread in the function invocation
-                        for value in values {
-                            int.push_value(value);
-                        }
+            let num_parameters = intrinsic.num_parameters();
+            assert!(
+                num_parameters.contains(&num_params_provided),
+                "Must call {} with between {} and {} params.",
+                intrinsic.name(),
+                num_parameters.start(),
+                num_parameters.end(),
+            );
+            for _ in num_params_provided..*num_parameters.end() {
+                int.push_value(Value::Null);
+            }
 
-                        assert!(
-                            num_params_provided == intrinsic.num_parameters(),
-                            "Must call function with the exact number of params."
+            int.push_eval(intrinsic.code());
+        }
+        Value::Closure(closure) => {
+            match &closure.code.keyword_pattern {
+                None => {
+                    if let Some(key) = keywords.keys().next() {
+                        panic!(
+                            "fn {} doesn't take keyword arguments, but was called with {}.",
+                            closure.display_name(),
+                            keyword_name(key),
                         );
+                    }
+                }
+                Some(keyword_pattern) => check_keyword_arguments(
+                    closure.display_name(),
+                    &closure.code.pattern,
+                    keyword_pattern,
+                    &keywords,
+                ),
+            }
+
+            let pattern = &closure.code.pattern;
+
+            let n_before = pattern.before_patterns.len();
+            let n_required_before = num_required_patterns(&pattern.before_patterns);
 
-                        int.push_eval(intrinsic.code());
+            match &pattern.spread_and_after_patterns {
+                None =>
+                // no spread, so must have exact number of params, less any with defaults
+                {
+                    if n_required_before == n_before {
+                        assert!(
+                            num_params_provided == n_before,
+                            "Must call fn {} with exactly {} params.",
+                            closure.display_name(),
+                            n_before,
+                        )
+                    } else {
+                        assert!(
+                            (n_required_before..=n_before).contains(&num_params_provided),
+                            "Must call fn {} with between {} and {} params.",
+                            closure.display_name(),
+                            n_required_before,
+                            n_before,
+                        )
                     }
-                    Value::Closure(closure) => {
-                        let pattern = &closure.code.pattern;
-
-                        let n_before = pattern.before_patterns.len();
-
-                        match &pattern.spread_and_after_patterns {
-                            None =>
-                            // no spread, so must have exact number of params
-                            {
-                                assert!(
-                                    num_params_provided == n_before,
-                                    "Must call fn {} with exactly {} params.",
-                                    closure.code.short_name(),
-                                    n_before,
-                                )
-                            }
-                            Some((_spread, after_params)) => {
-                                let n_after = after_params.len();
-                                assert!(
-                                    num_params_provided >= n_before + n_after,
-                                    "Must call fn {} function with at least {} params.",
-                                    closure.code.short_name(),
-                                    n_before + n_after,
-                                );
-                            }
-                        }
+                }
+                Some((_spread, after_params)) => {
+                    let n_required = n_required_before + num_required_patterns(after_params);
+                    assert!(
+                        num_params_provided >= n_required,
+                        "Must call fn {} with at least {} params.",
+                        closure.display_name(),
+                        n_required,
+                    );
+                }
+            }
 
-                        // the variable scope of the parameters extends lexical scope of the closure.
-                        let scope = Scope::extend(closure.parent_scope.clone());
+            if let Err(err) = int.check_call_depth(closure.display_name()) {
+                return send_call_error(int, err);
+            }
+            // the variable scope of the parameters extends lexical scope of the closure.
+            let scope = Scope::extend(closure.parent_scope.clone());
+
+            // move the interpreter into this scope and onto a new instruction stack.
+            int.push_fn_context(FunctionContext::new(scope));
+
+            // add the function parameter bindings in the new scope
+            let mut bindings = PatternBindings::new(false);
+            do_list_pattern_bindings_no_unwrap(int, pattern, values, &mut bindings);
+            if let Some(keyword_pattern) = &closure.code.keyword_pattern {
+                do_object_pattern_bindings(
+                    int,
+                    keyword_pattern,
+                    Value::Object(Rc::new(keywords)),
+                    &mut bindings,
+                );
+            }
 
-                        // move the interpreter into this scope and onto a new instruction stack.
-                        int.push_fn_context(FunctionContext::new(scope));
+            let body = closure.code.body.clone();
 
-                        // add the function parameter bindings in the new scope
-                        do_list_pattern_bindings_no_unwrap(int, pattern, values);
+            int.push_eval(body);
 
-                        let body = closure.code.body.clone();
+            // defaults are evaluated in the new scope, before the body
+            finish_pattern_bindings(int, bindings);
+        }
+        Value::Object(_) => match callable.well_known_method(WellKnownSymbol::Call) {
+            // callable objects receive themselves as the first parameter
+            Some(method) => {
+                let mut values = values;
+                values.insert(0, callable);
+                call_value_with_keywords(int, method, values, keywords);
+            }
+            None => panic!("Cannot call an object that doesn't implement the call symbol."),
+        },
+        _ => panic!(
+            "Cannot call {}, it isn't a function.",
+            int.display_nested(&callable)
+        ),
+    };
+}
 
-                        int.push_eval(body);
-                    }
-                    _ => panic!("Cannot call value other than closure."),
+fn keyword_name(key: &Key) -> String {
+    match key {
+        Key::Str(name) => name.clone(),
+        _ => format!("{:?}", key),
+    }
+}
+
+// Keyword arguments are bound with an object pattern, but are checked beforehand so
+// that the errors can mention the function. Parameters before the `*` can only be
+// passed by position.
+fn check_keyword_arguments(
+    fn_name: &str,
+    positional: &ast::ListPattern,
+    pattern: &ast::ObjectPattern,
+    keywords: &HashMap<Key, Value>,
+) {
+    let mut expected = Vec::with_capacity(pattern.patterns.len());
+    for p in &pattern.patterns {
+        let (name, required) = match p {
+            ast::ObjectSubPattern::Ident(name) => (name, true),
+            ast::ObjectSubPattern::List(name, _) => (name, true),
+            ast::ObjectSubPattern::Object(name, _) => (name, true),
+            ast::ObjectSubPattern::Keyed(ast::PatternKey::Str(name), pattern) => {
+                (name, !matches!(pattern, ast::ListSubPattern::Default(..)))
+            }
+            // other keys can't be passed as keyword arguments
+            ast::ObjectSubPattern::Keyed(..) => continue,
+        };
+        if required && !keywords.contains_key(&Key::Str(name.clone())) {
+            panic!("fn {} is missing the keyword argument {}.", fn_name, name);
+        }
+        expected.push(Key::Str(name.clone()));
+    }
+
+    // a spread or wildcard takes any other keyword arguments
+    if pattern.final_pattern.is_none() {
+        if let Some(key) = keywords.keys().find(|key| !expected.contains(key)) {
+            if is_positional_param(positional, key) {
+                panic!(
+                    "fn {} can only take {} by position, because it is before the * in its parameters.",
+                    fn_name,
+                    keyword_name(key),
+                );
+            }
+            panic!(
+                "fn {} got an unexpected keyword argument {}.",
+                fn_name,
+                keyword_name(key)
+            );
+        }
+    }
+}
+
+fn is_positional_param(pattern: &ast::ListPattern, key: &Key) -> bool {
+    let after = pattern
+        .spread_and_after_patterns
+        .iter()
+        .flat_map(|(_, after)| after);
+    pattern.before_patterns.iter().chain(after).any(|p| {
+        let p = match p {
+            ast::ListSubPattern::Default(p, _) => p,
+            p => p,
+        };
+        matches!((p, key), (ast::ListSubPattern::Ident(name), Key::Str(key)) if name == key)
+    })
+}
+
+// Pop the evaluated arguments of a call off the value stack, expanding any spreads.
+// Keyword arguments are returned separately.
+fn pop_arguments(
+    int: &mut Interpreter,
+    elems: &[ast::CallElem],
+) -> (Vec<Value>, HashMap<Key, Value>) {
+    // spreads mean this capacity isn't actually correct
+    let mut values = Vec::with_capacity(elems.len());
+    let mut keywords = HashMap::new();
+    for elem in elems {
+        match elem {
+            ast::CallElem::Spread(_) => {
+                let list = int.pop_value();
+                let list = match list {
+                    Value::List(l) => Rc::unwrap_or_clone(l),
+                    _ => panic!("Can only use the ... operator in a function call on a list."),
                 };
+                for val in list {
+                    values.push(val);
+                }
+            }
+            ast::CallElem::Elem(_) => {
+                values.push(int.pop_value());
+            }
+            ast::CallElem::Keyword(name, _) => {
+                let value = int.pop_value();
+                if keywords.insert(Key::Str(name.clone()), value).is_some() {
+                    panic!("The keyword argument {} was given more than once.", name);
+                }
+            }
+        };
+    }
+    (values, keywords)
+}
+
+fn push_arguments(int: &mut Interpreter, elems: &[ast::CallElem]) {
+    // splats: num_params_provided now dynamic
+    // count num params provided *after* eval???
+    // or eval splats later?
+    for elem in elems {
+        let expr = match elem {
+            ast::CallElem::Spread(expr) => expr,
+            ast::CallElem::Elem(expr) => expr,
+            ast::CallElem::Keyword(_, expr) => expr,
+        };
+        int.push_eval(expr.clone().into_eval());
+    }
+}
+
+impl Eval for ast::FunctionInvocation {
+    fn eval(self: Rc<Self>, int: &mut Interpreter) {
+        let self2 = self.clone();
+        int.push_eval(Rc::new(Custom::new(
+            "FunctionInvocationInner",
+            move |int| {
+                let callable = int.pop_value();
+                let (values, keywords) = pop_arguments(int, &self2.elems);
+                call_value_with_keywords(int, callable, values, keywords);
             },
         )));
 
         int.push_eval(self.base.clone().into_eval());
 
-        // splats: num_params_provided now dynamic
-        // count num params provided *after* eval???
-        // or eval splats later?
-        for elem in &self.elems {
-            let expr = match elem {
-                ast::ListElem::Spread(expr) => expr,
-                ast::ListElem::Elem(expr) => expr,
-            };
-            int.push_eval(expr.clone().into_eval());
-        }
+        push_arguments(int, &self.elems);
     }
     fn short_name(&self) -> &str {
         "FunctionInvocation"
     }
 }
+impl Eval for ast::MethodCall {
+    fn eval(self: Rc<Self>, int: &mut Interpreter) {
+        let self2 = self.clone();
+        int.push_eval(Rc::new(Custom::new("MethodCallInner", move |int| {
+            let base = int.pop_value();
+            let (mut values, keywords) = pop_arguments(int, &self2.elems);
+
+            // methods stored on the object take priority over functions in scope
+            let method = match &base {
+                Value::Object(obj) => obj.get(&Key::Str(self2.method.clone())).cloned(),
+                _ => None,
+            };
+            let method = method.or_else(|| int.current_scope().resolve_binding(&self2.method));
+            let method = method.unwrap_or_else(|| {
+                panic!(
+                    "Could not call method {:?}. It isn't a property of {} or a name in scope.",
+                    self2.method,
+                    int.display_nested(&base)
+                )
+            });
+
+            values.insert(0, base);
+            call_value_with_keywords(int, method, values, keywords);
+        })));
+
+        int.push_eval(self.base.clone().into_eval());
+
+        push_arguments(int, &self.elems);
+    }
+    fn short_name(&self) -> &str {
+        "MethodCall"
+    }
+}
+
 #[derive(Debug)]
 pub struct Handler {
-    match_arms: Vec<(u64, ast::HandleMatch)>,
+    // the symbol, pattern and block of each arm
+    match_arms: Vec<(u64, Rc<ast::LetPattern>, Rc<ast::Block>)>,
 }
 impl Eval for Handler {
     fn eval(self: Rc<Self>, int: &mut Interpreter) {
@@ -916,21 +1839,22 @@ impl Eval for Handler {
 
         let match_arm = self
             .match_arms
-            .clone()
-            .into_iter()
-            .find(|(sym, _)| *sym == symbol);
+            .iter()
+            .find(|(sym, pattern, _)| *sym == symbol && let_pattern_matches(int, pattern, &value))
+            .map(|(_, pattern, block)| (pattern.clone(), block.clone()));
 
-        if let Some((_, ast::HandleMatch { param, block, .. })) = match_arm {
+        if let Some((pattern, block)) = match_arm {
             int.push_eval(Rc::new(PopScope));
             int.push_eval(block);
             int.push_eval(Rc::new(LetInner {
-                pattern: Rc::new(ast::LetPattern::Ident(param)),
+                pattern,
+                mutable: false,
             }));
             // if PushScope added/consumed values, or changed the context, we would have to push an identity function here instead of value directly.
             int.push_value(value);
             int.push_eval(Rc::new(PushScope));
         } else {
-            // if there is no match arm that handles this effect, establish a passthrough.
+            // if there is no match arm that handles this effect, or none of their patterns match its value, establish a passthrough.
             // this means sending the effect upwards, then resuming with whatever value we get back
             int.push_eval(Rc::new(ContinueInner));
             int.push_eval(Rc::new(SendInner));
@@ -966,7 +1890,8 @@ impl Eval for CreateHandler {
         int.push_eval(Rc::new(Handler {
             match_arms: symbols
                 .into_iter()
-                .zip(self2.match_arms.into_iter())
+                .zip(self2.match_arms)
+                .map(|(symbol, arm)| (symbol, arm.pattern, arm.block))
                 .collect::<Vec<_>>(),
         }));
 
@@ -985,7 +1910,7 @@ impl Eval for ast::Handle {
 
         // eagerly evaluate the symbols
         for match_arm in &self.match_arms {
-            int.push_eval(Rc::new(match_arm.symbol.clone()));
+            int.push_eval(match_arm.symbol.clone().into_eval());
         }
     }
     fn short_name(&self) -> &str {
@@ -1017,7 +1942,7 @@ impl Eval for ast::SendExpr {
     fn eval(self: Rc<Self>, int: &mut Interpreter) {
         int.push_eval(Rc::new(SendInner));
 
-        int.push_eval(Rc::new(self.symbol.clone()));
+        int.push_eval(self.symbol.clone().into_eval());
 
         if let Some(expr) = &self.expr {
             int.push_eval(expr.clone().into_eval());
@@ -1108,6 +2033,33 @@ impl Eval for BreakInner {
     }
 }
 
+#[derive(Debug)]
+pub struct ReturnInner;
+impl Eval for ReturnInner {
+    fn eval(self: Rc<Self>, int: &mut Interpreter) {
+        let value = int.pop_value();
+        int.return_from_fn_context(value);
+    }
+    fn short_name(&self) -> &str {
+        "ReturnInner"
+    }
+}
+
+impl Eval for ast::Return {
+    fn eval(self: Rc<Self>, int: &mut Interpreter) {
+        int.push_eval(Rc::new(ReturnInner));
+
+        if let Some(expr) = &self.expr {
+            int.push_eval(expr.clone().into_eval());
+        } else {
+            int.push_value(Value::Null);
+        }
+    }
+    fn short_name(&self) -> &str {
+        "Return"
+    }
+}
+
 impl Eval for ast::Break {
     fn eval(self: Rc<Self>, int: &mut Interpreter) {
         int.push_eval(Rc::new(BreakInner));
@@ -1126,6 +2078,8 @@ impl Eval for ast::Break {
 #[derive(Debug)]
 pub struct WrapperFunction {
     pub body: Rc<dyn ast::Expression>,
+    // modules get a fresh scope of their own instead of extending the current one
+    pub module_path: Option<Rc<PathBuf>>,
 }
 impl Eval for WrapperFunction {
     fn eval(self: Rc<Self>, int: &mut Interpreter) {
@@ -1136,9 +2090,21 @@ impl Eval for WrapperFunction {
 
         let self2 = Rc::try_unwrap(self)
             .expect("Implementation error - Couldn't unwrap a WrapperFunction, it is aliased.");
-        let scope = Scope::extend(int.current_scope().clone());
+        let scope = match &self2.module_path {
+            Some(path) => Scope::module(int.root_scope(), path.clone()),
+            None => Scope::extend(int.current_scope().clone()),
+        };
 
         int.push_fn_context(FunctionContext::new(scope));
+        // Cache the module from inside its own context, so that it still happens if the body
+        // sends an effect and is continued by a handler.
+        if let Some(path) = self2.module_path {
+            int.push_eval(Rc::new(Custom::new("ModuleFinished", move |int| {
+                let value = int.pop_value();
+                int.finish_module(&path, &value);
+                int.push_value(value);
+            })));
+        }
         int.push_eval(self2.body.into_eval());
     }
 
@@ -1147,16 +2113,47 @@ impl Eval for WrapperFunction {
     }
 }
 
+// Resolves the parts of a location chain from `part` onwards. The value resolved so far is on top of
+// the value stack, followed by the values of any index expressions.
+#[derive(Debug)]
+pub struct LocationChainStep {
+    chain: Rc<ast::LocationChain>,
+    part: usize,
+}
+impl Eval for LocationChainStep {
+    fn eval(self: Rc<Self>, int: &mut Interpreter) {
+        let mut value = int.pop_value();
+        for (i, part) in self.chain.parts.iter().enumerate().skip(self.part) {
+            match part.resolve_value(int, value) {
+                Resolved::Value(v) => value = v,
+                Resolved::Call(method, args) => {
+                    // resolve the rest of the chain after the method returns
+                    int.push_eval(Rc::new(LocationChainStep {
+                        chain: self.chain.clone(),
+                        part: i + 1,
+                    }));
+                    call_value(int, method, args);
+                    return;
+                }
+            }
+        }
+        int.push_value(value);
+    }
+    fn short_name(&self) -> &str {
+        "LocationChainStep"
+    }
+}
+
 impl Eval for ast::LocationChain {
     fn eval(self: Rc<Self>, int: &mut Interpreter) {
-        let self2 = self.clone();
-        int.push_eval(Rc::new(Custom::new("LocationChainInner", move |int| {
-            let value = int.resolve_location_chain(&self2);
-            int.push_value(value);
-        })));
+        int.push_eval(Rc::new(LocationChainStep {
+            chain: self.clone(),
+            part: 0,
+        }));
 
-        if let ast::LocationChainBase::Expression(expr) = &self.base {
-            int.push_eval(expr.clone().into_eval());
+        match &self.base {
+            ast::LocationChainBase::Ident(ident) => int.push_eval(Rc::new(ident.clone())),
+            ast::LocationChainBase::Expression(expr) => int.push_eval(expr.clone().into_eval()),
         }
 
         for part in self.parts.iter() {
@@ -1171,10 +2168,18 @@ impl Eval for ast::LocationChain {
 impl Location for ast::DotLocation {
     fn push_exprs(&self, _int: &mut Interpreter) {}
 
-    fn resolve<'s, 'int>(
-        &'s self,
+    fn resolve_value(&self, int: &mut Interpreter, base: Value) -> Resolved {
+        Resolved::Value(
+            self.resolve(&mut || unreachable!(), &base, int.symbols())
+                .clone(),
+        )
+    }
+
+    fn resolve<'int>(
+        &self,
         _pop_value: &mut dyn FnMut() -> Value,
         base: &'int Value,
+        _symbols: &SymbolGenerator,
     ) -> &'int Value {
         let base = match base {
             Value::Object(obj) => obj,
@@ -1185,10 +2190,11 @@ impl Location for ast::DotLocation {
             None => panic!("The object does not contain the key {:?}.", self.prop),
         }
     }
-    fn resolve_mut<'s, 'int>(
-        &'s self,
+    fn resolve_mut<'int>(
+        &self,
         _pop_value: &mut dyn FnMut() -> Value,
         base: &'int mut Value,
+        _symbols: &SymbolGenerator,
     ) -> &'int mut Value {
         let base = match base {
             Value::Object(obj) => obj,
@@ -1219,14 +2225,42 @@ impl Location for ast::IndexLocation {
         int.push_eval(self.index.clone().into_eval());
     }
 
-    fn resolve<'s, 'int>(
-        &'s self,
+    fn resolve_value(&self, int: &mut Interpreter, base: Value) -> Resolved {
+        let index = int.pop_value();
+        // objects can implement indexing for keys they don't contain with the index symbol
+        if let Value::Object(obj) = &base {
+            if !obj.contains_key(&Key::from_value(&index)) {
+                if let Some(method) = base.well_known_method(WellKnownSymbol::Index) {
+                    return Resolved::Call(method, vec![base, index]);
+                }
+            }
+        }
+        let mut index = Some(index);
+        Resolved::Value(
+            self.resolve(&mut || index.take().unwrap(), &base, int.symbols())
+                .clone(),
+        )
+    }
+
+    fn resolve<'int>(
+        &self,
         pop_value: &mut dyn FnMut() -> Value,
         base: &'int Value,
+        symbols: &SymbolGenerator,
     ) -> &'int Value {
         let base = match base {
-            Value::List(obj) => obj,
-            _ => panic!("Can only use the . operator on objects."),
+            Value::List(list) => list,
+            Value::Object(obj) => {
+                let key = Key::from_value(&pop_value());
+                return match obj.get(&key) {
+                    Some(val) => val,
+                    None => panic!(
+                        "The object does not contain the key {}.",
+                        symbols.display_key(&key)
+                    ),
+                };
+            }
+            _ => panic!("Can only use the [] operator on lists and objects."),
         };
 
         let index = pop_value();
@@ -1240,18 +2274,33 @@ impl Location for ast::IndexLocation {
             None => panic!("The index {} is out of range.", index),
         }
     }
-    fn resolve_mut<'s, 'int>(
-        &'s self,
+    fn resolve_mut<'int>(
+        &self,
         pop_value: &mut dyn FnMut() -> Value,
         base: &'int mut Value,
+        symbols: &SymbolGenerator,
     ) -> &'int mut Value {
         let base = match base {
-            Value::List(obj) => obj,
-            _ => panic!("Can only use the . operator on objects."),
+            Value::List(list) => list,
+            Value::Object(obj) => {
+                let obj = match Rc::get_mut(obj) {
+                    Some(obj) => obj,
+                    None => panic!("Couldn't get the object as mut, it is aliased."),
+                };
+                let key = Key::from_value(&pop_value());
+                return match obj.get_mut(&key) {
+                    Some(val) => val,
+                    None => panic!(
+                        "The object does not contain the key {}.",
+                        symbols.display_key(&key)
+                    ),
+                };
+            }
+            _ => panic!("Can only use the [] operator on lists and objects."),
         };
         let base = match Rc::get_mut(base) {
             Some(base) => base,
-            None => panic!("Couldn't get the object as mut, it is aliased."),
+            None => panic!("Couldn't get the list as mut, it is aliased."),
         };
 
         let index = pop_value();
@@ -1,13 +1,26 @@
 use crate::ast;
-use std::{collections::HashMap, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    path::{Path, PathBuf},
+    rc::{Rc, Weak},
+};
 
-use crate::eval::Eval;
+use crate::convert::{FromValue, IntoValue};
+use crate::eval::{Custom, Eval};
+use crate::limits::{HeapMeasure, Limits, RESOURCE_EXHAUSTED};
+use crate::replay::{replay_error, ReplayLog};
+use crate::tasks::{ChannelId, Scheduler, TaskId, Wait, MAIN_TASK};
 use crate::{
-    eval_impls::{Handler, WrapperFunction, LoopContext},
-    intrinsics::{intrinsic_scope, Intrinsic},
+    eval_impls::{
+        call_value, send_call_error, send_runtime_error, Handler, LoopContext, WrapperFunction,
+    },
+    intrinsics::{intrinsic_scope, Intrinsic, WellKnownSymbol},
+    Program,
 };
 use ast::{Expression, Function, LocationChain};
 
+/// A key of a Kal object.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Key {
     Null,
@@ -17,17 +30,68 @@ pub enum Key {
     Str(String),
 }
 
+impl Key {
+    pub fn from_value(value: &Value) -> Key {
+        Key::try_from_value(value).unwrap_or_else(|| {
+            panic!(
+                "Can't use {:?} as an object key. Keys must be null, bools, ints, symbols or strings.",
+                value
+            )
+        })
+    }
+
+    pub fn try_from_value(value: &Value) -> Option<Key> {
+        match value {
+            Value::Null => Some(Key::Null),
+            Value::Bool(b) => Some(Key::Bool(*b)),
+            Value::Int(i) => Some(Key::Int(*i)),
+            Value::Symbol(s) => Some(Key::Symbol(*s)),
+            Value::Str(s) => Some(Key::Str(s.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/// A Kal value.
+///
+/// Functions, effects, tasks and channels are only handles to the interpreter's internals, so
+/// hosts can pass them around but not look inside them.
 #[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum Value {
     Null,
     Bool(bool),
     Int(i64),
+    // there's no syntax for floats yet, they come from JSON and the host
+    Float(f64),
+    Str(Rc<String>),
     List(Rc<Vec<Value>>),
     Object(Rc<HashMap<Key, Value>>),
+    #[doc(hidden)]
     Closure(Rc<Closure>),
     Symbol(u64),
+    #[doc(hidden)]
     Effect(Rc<Effect>),
+    #[doc(hidden)]
     Intrinsic(Intrinsic),
+    #[doc(hidden)]
+    NativeFunction(Rc<NativeFunction>),
+    #[doc(hidden)]
+    Task(TaskId),
+    #[doc(hidden)]
+    Sender(ChannelId),
+    #[doc(hidden)]
+    Receiver(ChannelId),
+}
+
+impl Value {
+    // Look up the implementation of some language-defined behaviour on an object.
+    pub(crate) fn well_known_method(&self, symbol: WellKnownSymbol) -> Option<Value> {
+        match self {
+            Value::Object(obj) => obj.get(&Key::Symbol(symbol.id())).cloned(),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -35,6 +99,8 @@ pub struct Closure {
     pub code: Rc<Function>,
     // captured scope immediately outside the closure
     pub parent_scope: Rc<Scope>,
+    // the name of a named function, or the binding the closure was first assigned to
+    pub name: Option<String>,
 }
 
 impl Closure {
@@ -42,8 +108,14 @@ impl Closure {
         Closure {
             code,
             parent_scope: scope,
+            name: None,
         }
     }
+
+    // for error messages
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or("<anonymous>")
+    }
 }
 
 impl PartialEq for Closure {
@@ -59,6 +131,44 @@ pub struct Effect {
     pub ctx: FunctionContext,
 }
 
+// A function provided by the host with `register_fn`.
+pub struct NativeFunction {
+    pub name: String,
+    function: NativeFn,
+}
+
+type NativeResult = Result<Value, crate::Error>;
+type PlainFn = dyn Fn(&[Value]) -> NativeResult;
+type WithInterpreterFn = dyn Fn(&mut Interpreter, &[Value]) -> NativeResult;
+
+enum NativeFn {
+    Plain(Box<PlainFn>),
+    WithInterpreter(Box<WithInterpreterFn>),
+}
+
+impl NativeFunction {
+    pub fn call(&self, int: &mut Interpreter, args: &[Value]) -> NativeResult {
+        match &self.function {
+            NativeFn::Plain(function) => function(args),
+            NativeFn::WithInterpreter(function) => function(int, args),
+        }
+    }
+}
+
+impl std::fmt::Debug for NativeFunction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("NativeFunction")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl PartialEq for NativeFunction {
+    fn eq(&self, other: &NativeFunction) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
 impl PartialEq for Effect {
     fn eq(&self, _other: &Effect) -> bool {
         false
@@ -66,45 +176,139 @@ impl PartialEq for Effect {
 }
 
 #[derive(Debug)]
-struct SymbolGenerator {
+pub struct SymbolGenerator {
     counter: u64,
+    // descriptions are only used for display and error messages
+    descriptions: HashMap<u64, String>,
+    // global symbols, shared by everything running in the interpreter
+    registry: HashMap<String, u64>,
 }
 
 impl SymbolGenerator {
     fn new() -> Self {
-        SymbolGenerator { counter: 0 }
+        let descriptions = WellKnownSymbol::ALL
+            .iter()
+            .map(|symbol| (symbol.id(), symbol.name().to_owned()))
+            .collect();
+        SymbolGenerator {
+            counter: 0,
+            descriptions,
+            registry: HashMap::new(),
+        }
     }
 
-    fn gen(&mut self) -> Value {
+    fn gen(&mut self, description: Option<String>) -> Value {
         let n = self.counter;
         self.counter += 1;
+        if let Some(description) = description {
+            self.descriptions.insert(n, description);
+        }
         Value::Symbol(n)
     }
+
+    fn gen_global(&mut self, key: String) -> Value {
+        match self.registry.get(&key) {
+            Some(n) => Value::Symbol(*n),
+            None => {
+                let symbol = self.gen(Some(key.clone()));
+                if let Value::Symbol(n) = symbol {
+                    self.registry.insert(key, n);
+                }
+                symbol
+            }
+        }
+    }
+
+    fn description(&self, symbol: u64) -> Option<&str> {
+        self.descriptions.get(&symbol).map(|d| d.as_str())
+    }
+
+    pub fn display(&self, symbol: u64) -> String {
+        match self.description(symbol) {
+            Some(description) => format!("symbol({:?})", description),
+            None => format!("symbol({})", symbol),
+        }
+    }
+
+    // How a key appears in error messages, eg. "name" or symbol("private").
+    pub fn display_key(&self, key: &Key) -> String {
+        match key {
+            Key::Null => "null".to_owned(),
+            Key::Bool(b) => b.to_string(),
+            Key::Int(i) => i.to_string(),
+            Key::Symbol(s) => self.display(*s),
+            Key::Str(s) => format!("{:?}", s),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Binding {
+    Value(Value),
+    // Bindings declared with `let mut` live in a shared cell. Closures share the scopes they
+    // capture, so the cell lets both the closure and the defining scope assign to the binding.
+    Cell(Rc<RefCell<Value>>),
 }
 
 #[derive(Debug)]
 pub struct Scope {
-    parent: Option<Rc<Scope>>,
-    bindings: HashMap<String, Value>,
+    pub parent: Option<Rc<Scope>>,
+    pub bindings: HashMap<String, Binding>,
+    // the file this is the top level scope of, if it is the top of a module
+    pub module_path: Option<Rc<PathBuf>>,
+}
+
+// Why a binding couldn't be resolved for assignment.
+#[derive(Debug, PartialEq)]
+pub enum BindingMutError {
+    NotFound,
+    Immutable,
 }
-impl Scope {
 
+impl Scope {
     pub fn with_bindings(parent: Option<Rc<Scope>>, bindings: HashMap<String, Value>) -> Self {
-        Self { parent, bindings }
+        Self {
+            parent,
+            bindings: bindings
+                .into_iter()
+                .map(|(name, value)| (name, Binding::Value(value)))
+                .collect(),
+            module_path: None,
+        }
     }
 
     pub fn extend(parent: Rc<Scope>) -> Rc<Self> {
         Rc::new(Self {
             parent: Some(parent),
             bindings: HashMap::new(),
+            module_path: None,
+        })
+    }
+
+    pub fn module(parent: Rc<Scope>, path: Rc<PathBuf>) -> Rc<Self> {
+        Rc::new(Self {
+            parent: Some(parent),
+            bindings: HashMap::new(),
+            module_path: Some(path),
         })
     }
 
-    pub fn resolve_binding<'scope>(self: &'scope Rc<Scope>, name: &str) -> Option<&'scope Value> {
+    // The file that the code using this scope was written in.
+    pub fn resolve_module_path(&self) -> Option<&Path> {
         let mut scope = self;
         loop {
-            if scope.bindings.contains_key(name) {
-                return Some(scope.bindings.get(name).unwrap());
+            if let Some(path) = &scope.module_path {
+                return Some(path);
+            }
+            scope = scope.parent.as_ref()?;
+        }
+    }
+
+    fn find_binding(&self, name: &str) -> Option<&Binding> {
+        let mut scope = self;
+        loop {
+            if let Some(binding) = scope.bindings.get(name) {
+                return Some(binding);
             }
             if let Some(parent) = &scope.parent {
                 scope = parent;
@@ -115,33 +319,20 @@ impl Scope {
         }
     }
 
-    pub fn resolve_binding_mut<'scope>(
-        self: &'scope mut Rc<Scope>,
-        name: &str,
-    ) -> Option<&'scope mut Value> {
-        // This will always succeed the first time since the current scope is never aliased.
-        let mut scope = Rc::get_mut(self).unwrap_or_else(|| {
-            panic!(
-                "Implementation error - get_mut failed in resolve_binding_mut for {:?}.",
-                &name,
-            )
-        });
-        loop {
-            if scope.bindings.contains_key(name) {
-                return Some(scope.bindings.get_mut(name).unwrap());
-            }
+    pub fn resolve_binding(&self, name: &str) -> Option<Value> {
+        match self.find_binding(name)? {
+            Binding::Value(value) => Some(value.clone()),
+            Binding::Cell(cell) => Some(cell.borrow().clone()),
+        }
+    }
 
-            if let Some(parent) = &mut scope.parent {
-                if let Some(parent) = Rc::get_mut(parent) {
-                    scope = parent;
-                } else {
-                    // Couldn't go further up the scope chain because it is branched
-                    return None;
-                }
-            } else {
-                // Couldn't go further up the scope chain because we reached the end.
-                return None;
-            }
+    // Get the cell of a `let mut` binding. Cells are shared, so this works even when the
+    // scope chain has been captured by closures.
+    pub fn resolve_binding_mut(&self, name: &str) -> Result<Rc<RefCell<Value>>, BindingMutError> {
+        match self.find_binding(name) {
+            Some(Binding::Cell(cell)) => Ok(cell.clone()),
+            Some(Binding::Value(_)) => Err(BindingMutError::Immutable),
+            None => Err(BindingMutError::NotFound),
         }
     }
 }
@@ -185,19 +376,319 @@ impl FunctionContext {
             sub_context_stack: vec![SubContext::new(SubContextType::Plain)],
         }
     }
+
+    pub(crate) fn push_eval(&mut self, eval: Rc<dyn Eval>) {
+        self.sub_context_stack
+            .last_mut()
+            .expect("Implementation error - no sub contexts.")
+            .eval_stack
+            .push(eval);
+    }
+
+    // Add the values this context refers to, including any handled contexts, to the roots
+    // being measured.
+    pub fn measure_heap(&self, measure: &mut HeapMeasure) {
+        measure.scope(&self.scope);
+        for sub_context in self.sub_context_stack.iter() {
+            sub_context
+                .value_stack
+                .iter()
+                .for_each(|value| measure.value(value));
+            if let SubContextType::Handle(_, ctx) = &sub_context.typ {
+                ctx.measure_heap(measure);
+            }
+        }
+    }
 }
+
+/// Runs Kal programs, and holds everything they share: registered functions, granted
+/// capabilities, imported modules and limits.
 pub struct Interpreter {
     sym_gen: SymbolGenerator,
     fn_context_stack: Vec<FunctionContext>,
+    // the function contexts of callers which are waiting for `call` to return
+    outer_fn_context_stacks: Vec<Vec<FunctionContext>>,
+    // every `let mut` binding that might still be alive, for collecting and reporting cycles
+    cells: Vec<(String, Weak<RefCell<Value>>)>,
+    // forget cells that have been freed once there are this many
+    cells_prune_at: usize,
+    // print the bindings which are still alive when the interpreter is dropped
+    pub(crate) report_leaks: bool,
+    // modules which have been imported, by canonical path
+    modules: HashMap<PathBuf, Module>,
+    // values that the host allows modules to import, by name
+    capabilities: HashMap<String, Value>,
+    // the values of modules after their manifests have been started, by canonical path
+    started_modules: HashMap<PathBuf, Value>,
+    // functions registered by the host, which are in scope in every module
+    native_functions: HashMap<String, Value>,
+    // how many more evals can run, if there is a limit
+    fuel: Option<u64>,
+    // the program which sent the effect that `run_until_effect` or `step` stopped at
+    suspended: Option<FunctionContext>,
+    // the source of a program started by `start_recorded`, and the answers to its effects so far
+    recording: Option<ReplayLog>,
+    // the tasks started with `spawn`, which take turns with the program
+    scheduler: Scheduler,
+    limits: Limits,
+    // at least as many bytes as the program holds in lists and objects, when there is a limit
+    heap_bytes_bound: usize,
+}
+
+/// Why [`Interpreter::run_until_effect`] or [`Interpreter::step`] stopped.
+#[derive(Debug, PartialEq)]
+pub enum RunStatus {
+    /// The program finished with this value.
+    Finished(Value),
+    /// The program sent an effect that it doesn't handle, which the host can answer with
+    /// [`Interpreter::continue_with`].
+    Suspended(SuspendedEffect),
+    /// [`Interpreter::step`] ran as many evals as it was asked to.
+    Yielded,
+    /// The fuel ran out. The program can carry on after [`Interpreter::set_fuel`].
+    OutOfFuel,
+}
+
+/// An effect which a program sent without handling it.
+#[derive(Debug, PartialEq)]
+pub struct SuspendedEffect {
+    /// The symbol the effect was sent with.
+    pub symbol: Value,
+    /// The value sent with the effect.
+    pub value: Value,
+}
+
+// A module which returns an object with `imports` declares everything it needs, and gets
+// it passed to its `main` function when it is started. eg.
+//     { imports: { file: "std::file", math: "./math.kal" }, main: fn({ file, math }) ... }
+// Imports starting with . or / are other modules, everything else is a capability.
+struct Manifest {
+    path: PathBuf,
+    imports: Vec<(Key, ManifestImport)>,
+    main: Value,
+}
+
+enum ManifestImport {
+    Capability(String),
+    Module(PathBuf),
+}
+
+fn manifest_error(path: &Path, problem: &str) -> crate::Error {
+    crate::Error::runtime(
+        "manifest",
+        format!("Module {} {}.", path.display(), problem),
+    )
+}
+
+impl Manifest {
+    // `None` if the module doesn't declare any imports.
+    fn from_value(path: &Path, value: &Value) -> Result<Option<Manifest>, crate::Error> {
+        let obj = match value {
+            Value::Object(obj) => obj,
+            _ => return Ok(None),
+        };
+        let imports = match obj.get(&Key::Str("imports".to_owned())) {
+            Some(Value::Object(imports)) => imports,
+            Some(_) => return Err(manifest_error(path, "has imports which aren't an object")),
+            None => return Ok(None),
+        };
+        let main = obj
+            .get(&Key::Str("main".to_owned()))
+            .ok_or_else(|| manifest_error(path, "declares imports, but has no main function"))?;
+
+        let mut manifest_imports: Vec<(Key, ManifestImport)> = Vec::with_capacity(imports.len());
+        for (key, specifier) in imports.iter() {
+            let specifier = match specifier {
+                Value::Str(s) => s,
+                _ => return Err(manifest_error(path, "has imports which aren't strings")),
+            };
+            let import = if specifier.starts_with('.') || specifier.starts_with('/') {
+                let dir = path.parent().unwrap_or(Path::new(""));
+                ManifestImport::Module(canonical_module_path(&dir.join(specifier.as_str()))?)
+            } else {
+                ManifestImport::Capability(specifier.to_string())
+            };
+            manifest_imports.push((key.clone(), import));
+        }
+        // start dependencies in a consistent order
+        manifest_imports.sort_by_key(|(key, _)| format!("{:?}", key));
+
+        Ok(Some(Manifest {
+            path: path.to_path_buf(),
+            imports: manifest_imports,
+            main: main.clone(),
+        }))
+    }
+}
+
+fn out_of_fuel() -> crate::Error {
+    crate::Error::runtime("out_of_fuel", "Ran out of fuel.")
+}
+
+pub enum Module {
+    // part way through being evaluated by a task. The module's body might be waiting, or
+    // suspended at an effect, while other code runs.
+    Loading(TaskId),
+    // the exported value
+    Loaded(Value),
+}
+
+// The modules that the function contexts on a task's stack are running in, outermost first.
+fn module_chain(stack: &[FunctionContext]) -> Vec<&Path> {
+    let mut chain: Vec<&Path> = Vec::new();
+    for path in stack
+        .iter()
+        .filter_map(|ctx| ctx.scope.resolve_module_path())
+    {
+        if chain.last() != Some(&path) {
+            chain.push(path);
+        }
+    }
+    chain
+}
+
+fn panic_import_cycle(chain: &[&Path], path: &Path) -> ! {
+    let start = chain.iter().position(|p| *p == path).unwrap_or(0);
+    let chain: Vec<String> = chain[start..]
+        .iter()
+        .copied()
+        .chain(std::iter::once(path))
+        .map(|p| p.display().to_string())
+        .collect();
+    panic!("Import cycle: {}", chain.join(" -> "));
+}
+
+fn canonical_module_path(path: &Path) -> Result<PathBuf, crate::Error> {
+    path.canonicalize().map_err(|err| {
+        crate::Error::runtime(
+            "module",
+            format!("Could not find module {}. Error: {}", path.display(), err),
+        )
+    })
+}
+
+fn parse_module(path: &Path) -> Result<Rc<dyn Expression>, crate::Error> {
+    let text = std::fs::read_to_string(path).map_err(|err| {
+        crate::Error::runtime(
+            "module",
+            format!("Failed to read module {}. Error: {}", path.display(), err),
+        )
+    })?;
+    let body = crate::kal_grammar::BlockInnerParser::new()
+        .parse(&text)
+        .map_err(|err| crate::Error::Parse {
+            message: format!("{} in module {}", err, path.display()),
+        })?;
+    Ok(body)
+}
+
+impl Drop for Interpreter {
+    fn drop(&mut self) {
+        if self.report_leaks {
+            for name in self.take_leaked_bindings() {
+                eprintln!(
+                    "Leaked the binding {}, it is part of a reference cycle.",
+                    name
+                );
+            }
+        }
+    }
 }
 
 impl Interpreter {
+    /// An interpreter with no registered functions, capabilities or limits.
     pub fn new() -> Self {
         let sym_gen = SymbolGenerator::new();
-        let scope = intrinsic_scope(None);
         Interpreter {
             sym_gen,
-            fn_context_stack: vec![FunctionContext::new(scope)],
+            // the outermost function context is created when something is run
+            fn_context_stack: Vec::new(),
+            outer_fn_context_stacks: Vec::new(),
+            cells: Vec::new(),
+            cells_prune_at: 64,
+            report_leaks: false,
+            modules: HashMap::new(),
+            capabilities: HashMap::new(),
+            started_modules: HashMap::new(),
+            native_functions: HashMap::new(),
+            fuel: None,
+            suspended: None,
+            recording: None,
+            scheduler: Scheduler::default(),
+            limits: Limits::default(),
+            heap_bytes_bound: 0,
+        }
+    }
+
+    /// Make a Rust function callable by Kal code as `name`.
+    pub fn register_fn(
+        &mut self,
+        name: impl Into<String>,
+        function: impl Fn(&[Value]) -> NativeResult + 'static,
+    ) {
+        self.register_native_fn(name.into(), NativeFn::Plain(Box::new(function)));
+    }
+
+    /// Make a Rust function callable by Kal code as `name`, converting its arguments from a list
+    /// to `Args` (usually a tuple) and its return value back to a Kal value.
+    pub fn register_typed_fn<Args: FromValue, Ret: IntoValue>(
+        &mut self,
+        name: impl Into<String>,
+        function: impl Fn(Args) -> Result<Ret, crate::Error> + 'static,
+    ) {
+        self.register_fn(name, move |args| {
+            let args = Args::from_value(&Value::List(Rc::new(args.to_vec())))?;
+            function(args)?.into_value()
+        });
+    }
+
+    /// Make a Rust function callable by Kal code as `name`. It can use the interpreter, eg. to
+    /// call Kal functions it was passed with `Interpreter::call`.
+    pub fn register_fn_with_interpreter(
+        &mut self,
+        name: impl Into<String>,
+        function: impl Fn(&mut Interpreter, &[Value]) -> NativeResult + 'static,
+    ) {
+        self.register_native_fn(name.into(), NativeFn::WithInterpreter(Box::new(function)));
+    }
+
+    fn register_native_fn(&mut self, name: String, function: NativeFn) {
+        let native = NativeFunction {
+            name: name.clone(),
+            function,
+        };
+        self.native_functions
+            .insert(name, Value::NativeFunction(Rc::new(native)));
+    }
+
+    // The scope that every module starts from, with the intrinsics and registered functions.
+    pub(crate) fn root_scope(&self) -> Rc<Scope> {
+        let intrinsics = intrinsic_scope(None);
+        if self.native_functions.is_empty() {
+            return intrinsics;
+        }
+        Rc::new(Scope::with_bindings(
+            Some(intrinsics),
+            self.native_functions.clone(),
+        ))
+    }
+
+    /// Call a Kal function from Rust and get its return value. This runs separately from
+    /// anything else being evaluated, so effects that the function doesn't handle are returned
+    /// as effect values instead of reaching the caller's handlers. Running out of fuel part way
+    /// through the call is an error with the kind "out_of_fuel".
+    pub fn call(&mut self, callable: Value, args: Vec<Value>) -> Result<Value, crate::Error> {
+        let outer_fn_contexts = std::mem::take(&mut self.fn_context_stack);
+        self.outer_fn_context_stacks.push(outer_fn_contexts);
+        self.start_eval(Rc::new(Custom::new("NativeCall", move |int| {
+            call_value(int, callable.clone(), args.clone());
+        })));
+        let status = self.run_steps(None);
+        self.fn_context_stack = self.outer_fn_context_stacks.pop().unwrap();
+        match status {
+            RunStatus::Finished(value) => Ok(value),
+            RunStatus::OutOfFuel => Err(out_of_fuel()),
+            status => unreachable!("Implementation error - {:?} without a step limit.", status),
         }
     }
 
@@ -251,83 +742,563 @@ impl Interpreter {
         println!("]");
     }
 
-    #[allow(clippy::let_and_return)]
-    pub fn eval(&mut self, expression: Rc<dyn Expression>) -> Value {
-        let wrapper_function = WrapperFunction { body: expression };
-        self.push_eval(Rc::new(wrapper_function));
-        let value_left_over = loop {
-            // function contexts
-            let value_left_over = loop {
-                // sub contexts (loop, handle)
-                let value_left_over = loop {
-                    let statement = self
-                        .current_eval_stack()
-                        .pop()
-                        .expect("Implementation error - no more values to pop.");
-
-                    statement.eval(self);
-
-                    if self.current_eval_stack().is_empty() {
-                        debug_assert_eq!(
-                            self.current_value_stack().len(),
-                            1,
-                            "There should only be one value left on the stack."
-                        );
-                        break self.pop_value();
-                    }
-                };
+    /// Run a program to completion. Running out of fuel is an error with the kind "out_of_fuel",
+    /// and the program is abandoned.
+    pub fn eval(&mut self, program: Program) -> Result<Value, crate::Error> {
+        self.run(Rc::new(WrapperFunction {
+            body: program.0,
+            module_path: None,
+        }))
+    }
+
+    /// Evaluate a file as the main module, so that it can import files relative to itself.
+    /// A file which can't be read is an error with the kind "module", and one which can't be
+    /// parsed is [`crate::Error::Parse`].
+    pub fn eval_file(&mut self, path: &Path) -> Result<Value, crate::Error> {
+        let path = canonical_module_path(path)?;
+        self.load_module(path)
+    }
+
+    fn load_module(&mut self, path: PathBuf) -> Result<Value, crate::Error> {
+        if let Some(Module::Loaded(value)) = self.modules.get(&path) {
+            return Ok(value.clone());
+        }
+        let body = parse_module(&path)?;
+        self.modules
+            .insert(path.clone(), Module::Loading(self.scheduler.current));
+        self.run(Rc::new(WrapperFunction {
+            body,
+            module_path: Some(Rc::new(path)),
+        }))
+    }
+
+    // Called at the end of a module's body, which is only reached once any effects it sent
+    // have been handled.
+    pub(crate) fn finish_module(&mut self, path: &Path, value: &Value) {
+        self.modules
+            .insert(path.to_path_buf(), Module::Loaded(value.clone()));
+        self.scheduler.module_loaded(path.to_path_buf());
+    }
+
+    /// Give modules started with `start_file` access to a capability, eg. "std::file".
+    pub fn grant(&mut self, name: impl Into<String>, value: Value) {
+        self.capabilities.insert(name.into(), value);
+    }
+
+    /// Start a module and everything it imports. Modules which return a manifest are checked
+    /// before anything is started, and refused if they need capabilities that haven't been
+    /// granted. Then each manifest's main function is called with its imports, dependencies first.
+    /// A manifest which isn't made up of an `imports` object and a `main` function is an error
+    /// with the kind "manifest".
+    pub fn start_file(&mut self, path: &Path) -> Result<Value, crate::Error> {
+        let path = canonical_module_path(path)?;
+        let mut plan = Vec::new();
+        let mut missing = Vec::new();
+        self.plan_module(path.clone(), &mut Vec::new(), &mut plan, &mut missing)?;
+        if !missing.is_empty() {
+            return Err(crate::Error::runtime(
+                "capability",
+                format!(
+                    "Refusing to start {}, because the host hasn't granted {}.",
+                    path.display(),
+                    missing.join(", "),
+                ),
+            ));
+        }
+
+        for manifest in plan {
+            let imports: HashMap<Key, Value> = manifest
+                .imports
+                .into_iter()
+                .map(|(key, import)| {
+                    let value = match import {
+                        ManifestImport::Capability(name) => self.capabilities[&name].clone(),
+                        ManifestImport::Module(path) => self.started_modules[&path].clone(),
+                    };
+                    (key, value)
+                })
+                .collect();
+            let main = manifest.main;
+            let imports = Value::Object(Rc::new(imports));
+            let value = self.run(Rc::new(Custom::new("StartModule", move |int| {
+                call_value(int, main.clone(), vec![imports.clone()]);
+            })))?;
+            self.started_modules.insert(manifest.path, value);
+        }
+        Ok(self.started_modules[&path].clone())
+    }
+
+    // Evaluate the module at `path` and the modules its manifest imports, adding the manifests
+    // to the plan after their dependencies.
+    fn plan_module(
+        &mut self,
+        path: PathBuf,
+        starting: &mut Vec<PathBuf>,
+        plan: &mut Vec<Manifest>,
+        missing: &mut Vec<String>,
+    ) -> Result<(), crate::Error> {
+        if self.started_modules.contains_key(&path) || plan.iter().any(|m| m.path == path) {
+            return Ok(());
+        }
+        if starting.contains(&path) {
+            let chain: Vec<&Path> = starting.iter().map(PathBuf::as_path).collect();
+            panic_import_cycle(&chain, &path);
+        }
 
-                self.pop_sub_context();
-                if self.current_fn_context().sub_context_stack.is_empty() {
-                    break value_left_over;
+        let value = self.load_module(path.clone())?;
+        let manifest = match Manifest::from_value(&path, &value)? {
+            Some(manifest) => manifest,
+            // modules without a manifest don't need starting
+            None => {
+                self.started_modules.insert(path, value);
+                return Ok(());
+            }
+        };
+
+        starting.push(path);
+        for (_, import) in manifest.imports.iter() {
+            match import {
+                ManifestImport::Capability(name) => {
+                    if !self.capabilities.contains_key(name) {
+                        missing.push(format!(
+                            "{} (imported by {})",
+                            name,
+                            manifest.path.display()
+                        ));
+                    }
+                }
+                ManifestImport::Module(dependency) => {
+                    self.plan_module(dependency.clone(), starting, plan, missing)?
                 }
+            }
+        }
+        starting.pop();
+        plan.push(manifest);
+        Ok(())
+    }
 
-                self.push_value(value_left_over);
-            };
-            self.pop_fn_context();
-            if self.fn_context_stack.is_empty() {
-                break value_left_over;
+    // Run something to completion, eg. a program or a module. If it runs out of fuel, whatever
+    // was part way through is thrown away, including the tasks it spawned and the modules it
+    // was loading, so that the interpreter can be used again after `set_fuel`.
+    fn run(&mut self, eval: Rc<dyn Eval>) -> Result<Value, crate::Error> {
+        self.start_eval(eval);
+        match self.run_steps(None) {
+            RunStatus::Finished(value) => Ok(value),
+            RunStatus::OutOfFuel => {
+                self.fn_context_stack.clear();
+                self.scheduler = Scheduler::default();
+                self.modules
+                    .retain(|_, module| matches!(module, Module::Loaded(_)));
+                Err(out_of_fuel())
+            }
+            status => unreachable!("Implementation error - {:?} without a step limit.", status),
+        }
+    }
+
+    /// Start running a program. Nothing is evaluated until `run_until_effect` or `step` is
+    /// called. A program that was suspended at an effect, or hadn't finished, is abandoned.
+    pub fn start(&mut self, program: Program) {
+        self.fn_context_stack.clear();
+        self.suspended = None;
+        self.recording = None;
+        self.scheduler = Scheduler::default();
+        self.start_eval(Rc::new(WrapperFunction {
+            body: program.0,
+            module_path: None,
+        }));
+    }
+
+    /// Like `start`, but the answers to the program's effects are logged, so that it can be
+    /// saved with `replay_log` whenever it is suspended. See [`Interpreter::replay`] for what
+    /// replaying can't do.
+    pub fn start_recorded(&mut self, source: &str) -> Result<(), crate::Error> {
+        let program = crate::parse(source)?;
+        self.start(program);
+        self.recording = Some(ReplayLog {
+            source: source.to_owned(),
+            answers: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Save the program's source and the answers to its effects so far, so that it can be
+    /// replayed up to the effect it is suspended at, even by another process. The program must
+    /// have been started by `start_recorded`, and every answer must be convertible to JSON.
+    pub fn replay_log(&self) -> Result<Vec<u8>, crate::Error> {
+        let recording = match (&self.recording, &self.suspended) {
+            (Some(recording), Some(_)) => recording,
+            (None, _) => {
+                return Err(replay_error(
+                    "Only programs started with start_recorded have a replay log, not ones run with start, eval or start_file.",
+                ))
+            }
+            (_, None) => {
+                return Err(replay_error(
+                    "Programs can only be saved while they are suspended at an effect.",
+                ))
+            }
+        };
+        recording.to_bytes()
+    }
+
+    /// Carry on with a program saved by `replay_log`. The program is run again from the start,
+    /// answering its effects from the log, until it is suspended at the effect it was saved at.
+    /// Anything the program did before then, including calling native functions, is done
+    /// again, so native functions must be registered the same way and should be deterministic.
+    /// It is an error with the kind "replay" if the program doesn't send the same effects.
+    pub fn replay(&mut self, bytes: &[u8]) -> Result<RunStatus, crate::Error> {
+        let log = ReplayLog::from_bytes(bytes)?;
+        self.start_recorded(&log.source)?;
+
+        // the program already got this far, so it doesn't need more fuel to replay
+        let fuel = self.fuel.take();
+        let mut status = self.run_until_effect();
+        for answer in log.answers {
+            if !matches!(status, RunStatus::Suspended(_)) {
+                break;
+            }
+            self.continue_with(answer);
+            status = self.run_until_effect();
+        }
+        self.fuel = fuel;
+
+        match status {
+            RunStatus::Suspended(_) => Ok(status),
+            _ => Err(replay_error(
+                "The replayed program didn't send the same effects as when it was saved.",
+            )),
+        }
+    }
+
+    fn start_eval(&mut self, eval: Rc<dyn Eval>) {
+        // the previous run finished the outermost function context
+        if self.fn_context_stack.is_empty() {
+            let scope = self.root_scope();
+            self.push_fn_context(FunctionContext::new(scope));
+        }
+        self.push_eval(eval);
+    }
+
+    /// Carry on running the program until it finishes, sends an effect it doesn't handle, or
+    /// runs out of fuel. A program that ran out of fuel can carry on after `set_fuel`.
+    pub fn run_until_effect(&mut self) -> RunStatus {
+        let status = self.run_steps(None);
+        self.suspend_at_effect(status)
+    }
+
+    /// Like `run_until_effect`, but stop with `RunStatus::Yielded` after `max_steps` evals, so
+    /// that a host can interleave several programs.
+    pub fn step(&mut self, max_steps: u64) -> RunStatus {
+        let status = self.run_steps(Some(max_steps));
+        self.suspend_at_effect(status)
+    }
+
+    /// Answer the effect that the program is suspended at. The value is the result of the
+    /// `send`, once the program is run again.
+    pub fn continue_with(&mut self, value: Value) {
+        let ctx = self
+            .suspended
+            .take()
+            .expect("The program isn't suspended at an effect, so it can't be continued.");
+        if let Some(recording) = &mut self.recording {
+            recording.answers.push(value.clone());
+        }
+        // the outermost function context receives the value of the program
+        let scope = self.root_scope();
+        self.push_fn_context(FunctionContext::new(scope));
+        self.push_fn_context(ctx);
+        self.push_value(value);
+    }
+
+    fn run_steps(&mut self, max_steps: Option<u64>) -> RunStatus {
+        assert!(
+            !self.fn_context_stack.is_empty(),
+            "There is nothing to run. Start a program, or answer the effect it is suspended at with continue_with."
+        );
+        let mut steps = 0;
+        loop {
+            if max_steps == Some(steps) {
+                return RunStatus::Yielded;
+            }
+            match &mut self.fuel {
+                Some(0) => return RunStatus::OutOfFuel,
+                Some(fuel) => *fuel -= 1,
+                None => (),
+            }
+            steps += 1;
+            if let Some(value) = self.step_once() {
+                return RunStatus::Finished(value);
+            }
+        }
+    }
+
+    // A program that finishes with an effect sent it without handling it, so keep the
+    // continuation for `continue_with`.
+    fn suspend_at_effect(&mut self, status: RunStatus) -> RunStatus {
+        let effect = match status {
+            RunStatus::Finished(Value::Effect(effect)) => effect,
+            status => return status,
+        };
+        let Effect { symbol, value, ctx } = Rc::try_unwrap(effect)
+            .expect("Couldn't get the context out of an effect. The effect was aliased when it shouldn't have been.");
+        self.suspended = Some(ctx);
+        RunStatus::Suspended(SuspendedEffect {
+            symbol: Value::Symbol(symbol),
+            value,
+        })
+    }
+
+    /// Limit how many evals can run before `run_until_effect` returns `RunStatus::OutOfFuel`.
+    /// `None` means there is no limit.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+    }
+
+    /// How much fuel is left, if there is a limit.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Limit how much memory programs can use. Going over a limit sends the `error` effect
+    /// with the kind "resource_exhausted".
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.heap_bytes_bound = 0;
+        if limits.max_heap_bytes.is_some() {
+            self.heap_bytes_bound = self.measure_heap_bytes();
+        }
+    }
+
+    /// The limits set by [`Interpreter::set_limits`].
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    // Check that the program can hold another list, object or string of about `bytes`.
+    pub(crate) fn allocate(&mut self, bytes: usize) -> Result<(), crate::Error> {
+        let max = match self.limits.max_heap_bytes {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        if self.heap_bytes_bound + bytes > max {
+            // some of what was allocated has probably been freed since
+            self.heap_bytes_bound = self.measure_heap_bytes();
+            if self.heap_bytes_bound + bytes > max {
+                return Err(crate::Error::runtime(
+                    RESOURCE_EXHAUSTED,
+                    format!(
+                        "The program can't hold more than {} bytes of lists, objects and strings.",
+                        max
+                    ),
+                ));
+            }
+        }
+        self.heap_bytes_bound += bytes;
+        Ok(())
+    }
+
+    // Check that another function call can be nested inside the ones in progress.
+    pub(crate) fn check_call_depth(&self, fn_name: &str) -> Result<(), crate::Error> {
+        let max = match self.limits.max_call_depth {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let outer_depth: usize = self.outer_fn_context_stacks.iter().map(Vec::len).sum();
+        if outer_depth + self.fn_context_stack.len() >= max {
+            return Err(crate::Error::runtime(
+                RESOURCE_EXHAUSTED,
+                format!(
+                    "Calling fn {} would nest more than {} function calls.",
+                    fn_name, max
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    // The approximate size of every list and object that the program can still reach.
+    fn measure_heap_bytes(&self) -> usize {
+        let mut measure = HeapMeasure::default();
+        let fn_contexts = self
+            .outer_fn_context_stacks
+            .iter()
+            .flatten()
+            .chain(self.fn_context_stack.iter())
+            .chain(self.suspended.iter());
+        for ctx in fn_contexts {
+            ctx.measure_heap(&mut measure);
+        }
+        self.scheduler.measure_heap(&mut measure);
+        let answers = self
+            .recording
+            .iter()
+            .flat_map(|recording| recording.answers.iter());
+        let modules = self.modules.values().filter_map(|module| match module {
+            Module::Loaded(value) => Some(value),
+            Module::Loading(_) => None,
+        });
+        let globals = modules
+            .chain(self.started_modules.values())
+            .chain(self.capabilities.values())
+            .chain(answers);
+        for value in globals {
+            measure.value(value);
+        }
+        measure.finish()
+    }
+
+    // Run a single eval, then leave any sub contexts and function contexts that it finished.
+    // Returns the value of the program once the outermost function context is finished.
+    fn step_once(&mut self) -> Option<Value> {
+        let statement = self
+            .current_eval_stack()
+            .pop()
+            .expect("Implementation error - no more values to pop.");
+
+        statement.eval(self);
+
+        if let Some(max) = self.limits.max_value_stack {
+            if self.current_value_stack().len() > max {
+                let err = crate::Error::runtime(
+                    RESOURCE_EXHAUSTED,
+                    format!(
+                        "Evaluating this would put more than {} values on the value stack.",
+                        max
+                    ),
+                );
+                send_runtime_error(self, err);
+            }
+        }
+
+        while self.current_eval_stack().is_empty() {
+            debug_assert_eq!(
+                self.current_value_stack().len(),
+                1,
+                "There should only be one value left on the stack."
+            );
+            let value_left_over = self.pop_value();
+
+            // sub contexts (loop, handle)
+            self.pop_sub_context();
+            if self.current_fn_context().sub_context_stack.is_empty() {
+                // function contexts
+                self.pop_fn_context();
+                if self.fn_context_stack.is_empty() {
+                    if self.scheduler.current == MAIN_TASK
+                        || !self.outer_fn_context_stacks.is_empty()
+                    {
+                        return Some(value_left_over);
+                    }
+                    // a spawned task finished, so let another one run
+                    self.scheduler.finish(value_left_over);
+                    self.switch_task();
+                    continue;
+                }
             }
             self.push_value(value_left_over);
+        }
+        None
+    }
+
+    // Start a task which calls `callable` once the current task waits for something.
+    pub(crate) fn spawn_task(&mut self, callable: Value) -> Value {
+        let mut ctx = FunctionContext::new(self.root_scope());
+        ctx.push_eval(Rc::new(Custom::new("TaskMain", move |int| {
+            call_value(int, callable.clone(), Vec::new());
+        })));
+        Value::Task(self.scheduler.spawn(vec![ctx]))
+    }
+
+    // Give up the current task's turn until the wait is over, and carry on with the next task
+    // that is ready. The result of the wait ends up on the value stack once the task runs again.
+    pub(crate) fn wait_task(&mut self, wait: Wait) {
+        assert!(
+            self.outer_fn_context_stacks.is_empty(),
+            "Tasks can't wait inside a call from a native function."
+        );
+        let checked = match &wait {
+            Wait::Task(id) => self.scheduler.check_task(*id),
+            Wait::Join(ids) => ids.iter().try_for_each(|id| self.scheduler.check_task(*id)),
+            _ => Ok(()),
         };
-        value_left_over
+        if let Err(err) = checked {
+            return send_call_error(self, err);
+        }
+        let stack = std::mem::take(&mut self.fn_context_stack);
+        self.scheduler.park(stack, wait);
+        self.switch_task();
+    }
+
+    fn switch_task(&mut self) {
+        let (stack, result) = self.scheduler.next_ready();
+        self.fn_context_stack = stack;
+        match result {
+            Some(Ok(value)) => self.push_value(value),
+            Some(Err(err)) => send_call_error(self, err),
+            None => (),
+        }
     }
 
-    pub fn branch_scope(&mut self) -> Rc<Scope> {
+    // Make a channel which can hold `capacity` values, returning `[sender, receiver]`.
+    pub(crate) fn channel(&mut self, capacity: usize) -> Value {
+        let id = self.scheduler.channel(capacity);
+        Value::List(Rc::new(vec![Value::Sender(id), Value::Receiver(id)]))
+    }
+
+    // Send without waiting if the channel has room, or give the value back.
+    pub(crate) fn try_send(&mut self, id: ChannelId, value: Value) -> Option<Value> {
+        self.scheduler.try_send(id, value)
+    }
+
+    pub(crate) fn close_channel(&mut self, id: ChannelId) {
+        self.scheduler.close(id);
+    }
+
+    // The virtual time used by `sleep`.
+    pub(crate) fn clock(&self) -> u64 {
+        self.scheduler.clock
+    }
+
+    pub(crate) fn sleep_task(&mut self, ticks: u64) {
+        let wake = self.scheduler.clock + ticks;
+        self.wait_task(Wait::Sleep(wake));
+    }
+
+    pub(crate) fn branch_scope(&mut self) -> Rc<Scope> {
         let scope1 = Scope::extend(self.current_fn_context().scope.clone());
         let scope2 = Scope::extend(self.current_fn_context().scope.clone());
         self.current_fn_context().scope = scope1;
         scope2
     }
 
-    pub fn current_fn_context(&mut self) -> &mut FunctionContext {
+    pub(crate) fn current_fn_context(&mut self) -> &mut FunctionContext {
         self.fn_context_stack
             .last_mut()
             .expect("Implementation error - no function contexts.")
     }
 
-    pub fn push_fn_context(&mut self, ctx: FunctionContext) {
+    pub(crate) fn push_fn_context(&mut self, ctx: FunctionContext) {
         self.fn_context_stack.push(ctx);
     }
 
-    pub fn pop_fn_context(&mut self) -> FunctionContext {
+    pub(crate) fn pop_fn_context(&mut self) -> FunctionContext {
         self.fn_context_stack
             .pop()
             .expect("Implementation error - no more function contexts to pop.")
     }
 
-    pub fn current_sub_context(&mut self) -> &mut SubContext {
+    pub(crate) fn current_sub_context(&mut self) -> &mut SubContext {
         self.current_fn_context()
             .sub_context_stack
             .last_mut()
             .expect("Implementation error - no sub contexts.")
     }
 
-    pub fn push_sub_context(&mut self, ctx: SubContext) {
+    pub(crate) fn push_sub_context(&mut self, ctx: SubContext) {
         self.current_fn_context().sub_context_stack.push(ctx);
     }
 
-    pub fn pop_sub_context(&mut self) -> SubContext {
+    pub(crate) fn pop_sub_context(&mut self) -> SubContext {
         // release scopes that were created while the subcontext was active
         // we do this because we can't branch scopes and also have mutability.
         // this means that the subcontexts can't own scopes, but they *can* remember how many they created.
@@ -342,39 +1313,51 @@ impl Interpreter {
             .expect("Implementation error - no more sub contexts to pop.")
     }
 
-    pub fn current_eval_stack(&mut self) -> &mut Vec<Rc<dyn Eval>> {
+    // Abandon the rest of the current function (including any loops or handlers it is
+    // in the middle of) so that it finishes with the given value.
+    pub(crate) fn return_from_fn_context(&mut self, value: Value) {
+        while !self.current_fn_context().sub_context_stack.is_empty() {
+            self.pop_sub_context();
+        }
+        self.current_fn_context()
+            .sub_context_stack
+            .push(SubContext::new(SubContextType::Plain));
+        self.push_value(value);
+    }
+
+    pub(crate) fn current_eval_stack(&mut self) -> &mut Vec<Rc<dyn Eval>> {
         &mut self.current_sub_context().eval_stack
     }
 
-    pub fn current_value_stack(&mut self) -> &mut Vec<Value> {
+    pub(crate) fn current_value_stack(&mut self) -> &mut Vec<Value> {
         &mut self.current_sub_context().value_stack
     }
 
-    pub fn push_eval(&mut self, eval: Rc<dyn Eval>) {
+    pub(crate) fn push_eval(&mut self, eval: Rc<dyn Eval>) {
         self.current_eval_stack().push(eval)
     }
 
-    pub fn push_value(&mut self, value: Value) {
+    pub(crate) fn push_value(&mut self, value: Value) {
         self.current_value_stack().push(value)
     }
 
-    pub fn pop_value(&mut self) -> Value {
+    pub(crate) fn pop_value(&mut self) -> Value {
         self.current_value_stack()
             .pop()
             .expect("Implementation error - not enough values on value_stack.")
     }
 
-    pub fn current_scope(&mut self) -> &mut Rc<Scope> {
+    pub(crate) fn current_scope(&mut self) -> &mut Rc<Scope> {
         &mut self.current_fn_context().scope
     }
 
-    pub fn push_scope(&mut self) {
+    pub(crate) fn push_scope(&mut self) {
         let scope = self.current_scope();
         *scope = Scope::extend(scope.clone());
         self.current_sub_context().num_scopes += 1;
     }
 
-    pub fn pop_scope(&mut self) {
+    pub(crate) fn pop_scope(&mut self) {
         let scope = self.current_scope();
         *scope = scope
             .parent
@@ -384,61 +1367,240 @@ impl Interpreter {
         self.current_sub_context().num_scopes -= 1;
     }
 
-    pub fn create_binding(&mut self, name: String, value: Value) {
+    pub(crate) fn create_binding(&mut self, name: String, value: Value) {
+        self.create_binding_with_mutability(name, value, false);
+    }
+
+    pub(crate) fn create_mutable_binding(&mut self, name: String, value: Value) {
+        self.create_binding_with_mutability(name, value, true);
+    }
+
+    fn create_binding_with_mutability(&mut self, name: String, value: Value, mutable: bool) {
+        let binding = if mutable {
+            let cell = Rc::new(RefCell::new(value));
+            self.track_cell(name.clone(), &cell);
+            Binding::Cell(cell)
+        } else {
+            Binding::Value(value)
+        };
         // This should always succeed because the current scope will never be aliased since we branch it
         // when creating closures.
-        Rc::get_mut(&mut self.current_fn_context().scope)
-            .unwrap_or_else(|| {
-                panic!(
-                    "Implementation error - get_mut failed in create_binding for {:?}.",
-                    &name,
-                )
-            })
-            .bindings
-            .insert(name, value);
-    }
-
-    pub fn resolve_location_chain(&mut self, location_chain: &LocationChain) -> Value {
-        let fnctx = self.current_fn_context();
+        let scope = Rc::get_mut(&mut self.current_fn_context().scope).unwrap_or_else(|| {
+            panic!(
+                "Implementation error - get_mut failed in create_binding for {:?}.",
+                &name,
+            )
+        });
+        // shadowing a binding in the same scope also replaces its mutability
+        scope.bindings.insert(name, binding);
+    }
+
+    fn track_cell(&mut self, name: String, cell: &Rc<RefCell<Value>>) {
+        if self.cells.len() >= self.cells_prune_at {
+            self.cells.retain(|(_, cell)| cell.strong_count() > 0);
+            self.cells_prune_at = (self.cells.len() * 2).max(64);
+        }
+        self.cells.push((name, Rc::downgrade(cell)));
+    }
+
+    /// Break any reference cycles which go through `let mut` bindings. Returns the number of
+    /// bindings that were only reachable from cycles, which are set to null.
+    pub fn collect_cycles(&mut self) -> usize {
+        let cells = self
+            .cells
+            .iter()
+            .filter_map(|(_, cell)| cell.upgrade())
+            .collect();
+        crate::cycles::collect_cycles(cells)
+    }
+
+    /// Finish with the interpreter, and get the names of the `let mut` bindings which
+    /// are still alive because they are part of a reference cycle.
+    pub fn leaked_bindings(mut self) -> Vec<String> {
+        self.report_leaks = false;
+        self.take_leaked_bindings()
+    }
+
+    fn take_leaked_bindings(&mut self) -> Vec<String> {
+        self.fn_context_stack.clear();
+        self.suspended = None;
+        self.scheduler = Scheduler::default();
+        self.modules.clear();
+        self.started_modules.clear();
+        self.capabilities.clear();
+        self.native_functions.clear();
+        std::mem::take(&mut self.cells)
+            .into_iter()
+            .filter(|(_, cell)| cell.strong_count() > 0)
+            .map(|(name, _)| name)
+            .collect()
+    }
+
+    pub(crate) fn assign_location_chain(&mut self, location_chain: &LocationChain, value: Value) {
+        let symbols = &self.sym_gen;
+        let fnctx = self
+            .fn_context_stack
+            .last_mut()
+            .expect("Implementation error - no function contexts.");
         let scope = &fnctx.scope;
         let value_stack = &mut fnctx.sub_context_stack.last_mut().unwrap().value_stack;
 
         let mut pop_value = || value_stack.pop().unwrap();
 
-        let val = match &location_chain.base {
-            ast::LocationChainBase::Ident(ident) => scope.resolve_binding(&ident).unwrap_or_else(|| panic!("Could not resolve name {:?}.", ident)).clone(),
-            ast::LocationChainBase::Expression(_) => pop_value(),
+        let cell = match &location_chain.base {
+            ast::LocationChainBase::Ident(ident) => match scope.resolve_binding_mut(ident) {
+                Ok(cell) => cell,
+                Err(BindingMutError::NotFound) => panic!("Cannot assign to {}, because it is not defined.", ident),
+                Err(BindingMutError::Immutable) => panic!("Cannot assign to {}, because it was not declared with `let mut`.", ident),
+            },
+            _ => panic!("Implementation error - grammar should not allow a LocationChainExpression on the left hand side of an assignment."),
         };
-        let mut val_ref = &val;
+        let mut target = cell.borrow_mut();
+        let mut val_ref_mut = &mut *target;
         for part in location_chain.parts.iter() {
-            val_ref = part.resolve(&mut pop_value, val_ref);
+            val_ref_mut = part.resolve_mut(&mut pop_value, val_ref_mut, symbols);
         }
 
-        val_ref.clone()
+        *val_ref_mut = value;
     }
 
-    pub fn resolve_location_chain_mut(&mut self, location_chain: &LocationChain) -> &mut Value {
-        let fnctx = self.current_fn_context();
-        let scope = &mut fnctx.scope;
-        let value_stack = &mut fnctx.sub_context_stack.last_mut().unwrap().value_stack;
-
-        let mut pop_value = || value_stack.pop().unwrap();
+    // Evaluate a module in its own function context, or reuse its value if it has already been
+    // imported. The value ends up on the value stack. Relative paths are resolved against the
+    // file that the importing code is in.
+    pub(crate) fn import_module(&mut self, specifier: &str) {
+        let importer = self
+            .current_scope()
+            .resolve_module_path()
+            .map(Path::to_path_buf);
+        let path = match &importer {
+            Some(importer) => importer.parent().unwrap_or(Path::new("")).join(specifier),
+            None => PathBuf::from(specifier),
+        };
+        match canonical_module_path(&path) {
+            Ok(path) => self.import_path(path),
+            Err(err) => send_call_error(self, err),
+        }
+    }
 
-        let mut val_ref_mut = match &location_chain.base {
-            ast::LocationChainBase::Ident(ident) => {
-                scope.resolve_binding_mut(&ident).unwrap()
+    fn import_path(&mut self, path: PathBuf) {
+        match self.modules.get(&path) {
+            Some(Module::Loaded(value)) => {
+                let value = value.clone();
+                self.push_value(value);
+                return;
             }
-            _ => panic!("Implementation error - grammar should not allow a LocationChainExpression on the left hand side of an assignment."),
+            Some(Module::Loading(task)) if *task == self.scheduler.current => {
+                // The module might also have been left part way through, eg. if a handler broke
+                // out of an effect it sent. Then it's loaded again.
+                let chain = module_chain(&self.fn_context_stack);
+                if chain.contains(&path.as_path()) {
+                    panic_import_cycle(&chain, &path);
+                }
+            }
+            Some(Module::Loading(_)) => {
+                // wait for the other task to finish the module, then import it again
+                let path2 = path.clone();
+                self.push_eval(Rc::new(Custom::new("ImportAgain", move |int| {
+                    int.pop_value();
+                    int.import_path(path2.clone());
+                })));
+                self.wait_task(Wait::Module(path));
+                return;
+            }
+            None => (),
+        }
+
+        let body = match parse_module(&path) {
+            Ok(body) => body,
+            Err(err) => return send_call_error(self, err),
         };
-        for part in location_chain.parts.iter() {
-            val_ref_mut = part.resolve_mut(&mut pop_value, val_ref_mut);
+        self.modules
+            .insert(path.clone(), Module::Loading(self.scheduler.current));
+        self.push_eval(Rc::new(WrapperFunction {
+            body,
+            module_path: Some(Rc::new(path)),
+        }));
+    }
+
+    pub(crate) fn gen_symbol(&mut self, description: Option<String>) -> Value {
+        self.sym_gen.gen(description)
+    }
+
+    /// Get the symbol registered under `key`, creating it if this is the first time the key is used.
+    pub fn global_symbol(&mut self, key: String) -> Value {
+        self.sym_gen.gen_global(key)
+    }
+
+    /// The description a symbol was made with, eg. `"request"` for `symbol("request")`.
+    pub fn symbol_description(&self, symbol: u64) -> Option<&str> {
+        self.sym_gen.description(symbol)
+    }
+
+    /// Show a value the way Kal's `str()` does.
+    pub fn display_value(&self, value: &Value) -> String {
+        match value {
+            Value::Str(s) => s.to_string(),
+            _ => self.display_nested(value),
         }
+    }
 
-        val_ref_mut
+    pub(crate) fn symbols(&self) -> &SymbolGenerator {
+        &self.sym_gen
     }
 
-    pub fn gen_symbol(&mut self) -> Value {
-        self.sym_gen.gen()
+    // Like display_value, but strings are quoted so that they can be told apart inside lists and
+    // objects. Also used to show values in error messages.
+    pub(crate) fn display_nested(&self, value: &Value) -> String {
+        match value {
+            Value::Null => "null".to_owned(),
+            Value::Bool(b) => b.to_string(),
+            Value::Int(i) => i.to_string(),
+            // always has a decimal point, so it can be told apart from an int
+            Value::Float(f) => format!("{:?}", f),
+            Value::Str(s) => format!("{:?}", s),
+            Value::List(list) => {
+                let elems = list
+                    .iter()
+                    .map(|v| self.display_nested(v))
+                    .collect::<Vec<_>>();
+                format!("[{}]", elems.join(", "))
+            }
+            Value::Object(obj) => {
+                if obj.is_empty() {
+                    return "{}".to_owned();
+                }
+                let mut elems = obj
+                    .iter()
+                    .map(|(k, v)| {
+                        let key = match k {
+                            Key::Str(s) => s.clone(),
+                            Key::Null => "[null]".to_owned(),
+                            Key::Bool(b) => format!("[{}]", b),
+                            Key::Int(i) => format!("[{}]", i),
+                            Key::Symbol(s) => {
+                                format!("[{}]", self.display_nested(&Value::Symbol(*s)))
+                            }
+                        };
+                        format!("{}: {}", key, self.display_nested(v))
+                    })
+                    .collect::<Vec<_>>();
+                // HashMap iteration order is random, so sort to make output stable.
+                elems.sort();
+                format!("{{ {} }}", elems.join(", "))
+            }
+            Value::Closure(_) => "fn".to_owned(),
+            Value::Symbol(s) => self.sym_gen.display(*s),
+            Value::Effect(effect) => format!(
+                "effect({}, {})",
+                self.display_nested(&Value::Symbol(effect.symbol)),
+                self.display_nested(&effect.value)
+            ),
+            Value::Intrinsic(intrinsic) => intrinsic.name(),
+            Value::NativeFunction(native) => native.name.clone(),
+            Value::Task(id) => format!("task({})", id),
+            Value::Sender(id) => format!("sender({})", id),
+            Value::Receiver(id) => format!("receiver({})", id),
+        }
     }
 }
 
@@ -1,6 +1,13 @@
-use crate::{eval::Custom, eval::Eval, interpreter};
-use interpreter::{Scope, Value};
-use std::{collections::HashMap, fmt::Debug, rc::Rc};
+use crate::{
+    eval::Custom,
+    eval::Eval,
+    eval_impls::{call_value, send_call_error},
+    interpreter, json,
+    limits::{list_bytes, str_bytes, value_bytes},
+    tasks::{channel_bytes, ChannelId, Wait},
+};
+use interpreter::{Key, Scope, Value};
+use std::{collections::HashMap, fmt::Debug, ops::RangeInclusive, rc::Rc};
 
 pub fn intrinsic_scope(parent: Option<Rc<Scope>>) -> Rc<Scope> {
     Rc::new(Scope::with_bindings(parent, {
@@ -9,14 +16,115 @@ pub fn intrinsic_scope(parent: Option<Rc<Scope>>) -> Rc<Scope> {
         use self::Intrinsic::*;
         use Value::Intrinsic;
         map.insert("symbol".into(), Intrinsic(Symbol));
+        map.insert("symbol_for".into(), Intrinsic(SymbolFor));
+        map.insert("str".into(), Intrinsic(Str));
+        map.insert("collect_cycles".into(), Intrinsic(CollectCycles));
+        map.insert("debug_leaks".into(), Intrinsic(DebugLeaks));
+        map.insert("import".into(), Intrinsic(Import));
+        map.insert("spawn".into(), Intrinsic(Spawn));
+        map.insert("await".into(), Intrinsic(Await));
+        map.insert("join".into(), Intrinsic(Join));
+        map.insert("sleep".into(), Intrinsic(Sleep));
+        map.insert("now".into(), Intrinsic(Now));
+        map.insert("channel".into(), Intrinsic(Channel));
+        // send is a keyword, so this can only be called as a method, eg. tx->send(value)
+        map.insert("send".into(), Intrinsic(ChannelSend));
+        map.insert("recv".into(), Intrinsic(Recv));
+        map.insert("close".into(), Intrinsic(Close));
+        map.insert("select".into(), Intrinsic(Select));
+        map.insert(
+            "json".into(),
+            Value::Object(Rc::new(HashMap::from([
+                (Key::Str("parse".into()), Intrinsic(JsonParse)),
+                (Key::Str("stringify".into()), Intrinsic(JsonStringify)),
+            ]))),
+        );
+
+        map.insert(
+            "symbols".into(),
+            Value::Object(Rc::new(
+                WellKnownSymbol::ALL
+                    .iter()
+                    .map(|symbol| (Key::Str(symbol.name().into()), Value::Symbol(symbol.id())))
+                    .collect(),
+            )),
+        );
 
         map
     }))
 }
 
+/// Symbols which the language itself looks for on objects, eg. `{ [symbols.add]: fn(a, b) ... }`
+/// makes an object usable with the `+` operator.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WellKnownSymbol {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Eq,
+    Compare,
+    Index,
+    Call,
+    Iter,
+    Display,
+    Yield,
+    Error,
+}
+
+impl WellKnownSymbol {
+    pub const ALL: [WellKnownSymbol; 12] = {
+        use WellKnownSymbol::*;
+        [
+            Add, Subtract, Multiply, Divide, Eq, Compare, Index, Call, Iter, Display, Yield, Error,
+        ]
+    };
+
+    pub fn name(&self) -> &'static str {
+        use WellKnownSymbol::*;
+        match self {
+            Add => "add",
+            Subtract => "subtract",
+            Multiply => "multiply",
+            Divide => "divide",
+            Eq => "eq",
+            Compare => "compare",
+            Index => "index",
+            Call => "call",
+            Iter => "iter",
+            Display => "display",
+            Yield => "yield",
+            Error => "error",
+        }
+    }
+
+    // Well-known symbols are allocated downwards from the top of the
+    // symbol space so that they never collide with generated symbols.
+    pub fn id(&self) -> u64 {
+        u64::MAX - *self as u64
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Intrinsic {
     Symbol,
+    SymbolFor,
+    Str,
+    CollectCycles,
+    DebugLeaks,
+    Import,
+    JsonParse,
+    JsonStringify,
+    Spawn,
+    Await,
+    Join,
+    Sleep,
+    Now,
+    Channel,
+    ChannelSend,
+    Recv,
+    Close,
+    Select,
 }
 
 impl Intrinsic {
@@ -24,6 +132,23 @@ impl Intrinsic {
         use Intrinsic::*;
         match self {
             Symbol => "symbol".to_owned(),
+            SymbolFor => "symbol_for".to_owned(),
+            Str => "str".to_owned(),
+            CollectCycles => "collect_cycles".to_owned(),
+            DebugLeaks => "debug_leaks".to_owned(),
+            Import => "import".to_owned(),
+            JsonParse => "json.parse".to_owned(),
+            JsonStringify => "json.stringify".to_owned(),
+            Spawn => "spawn".to_owned(),
+            Await => "await".to_owned(),
+            Join => "join".to_owned(),
+            Sleep => "sleep".to_owned(),
+            Now => "now".to_owned(),
+            Channel => "channel".to_owned(),
+            ChannelSend => "send".to_owned(),
+            Recv => "recv".to_owned(),
+            Close => "close".to_owned(),
+            Select => "select".to_owned(),
         }
     }
 
@@ -31,19 +156,269 @@ impl Intrinsic {
         use Intrinsic::*;
         match self {
             Symbol => symbol(),
+            SymbolFor => symbol_for(),
+            Str => str(),
+            CollectCycles => collect_cycles(),
+            DebugLeaks => debug_leaks(),
+            Import => import(),
+            JsonParse => json_parse(),
+            JsonStringify => json_stringify(),
+            Spawn => spawn(),
+            Await => await_task(),
+            Join => join(),
+            Sleep => sleep(),
+            Now => now(),
+            Channel => channel(),
+            ChannelSend => channel_send(),
+            Recv => recv(),
+            Close => close(),
+            Select => select(),
         }
     }
-    pub fn num_parameters(&self) -> usize {
+    // Optional parameters are passed as null when they aren't provided.
+    pub fn num_parameters(&self) -> RangeInclusive<usize> {
         use Intrinsic::*;
         match self {
-            Symbol => 0,
+            Symbol => 0..=1,
+            SymbolFor => 1..=1,
+            Str => 1..=1,
+            CollectCycles => 0..=0,
+            DebugLeaks => 0..=0,
+            Import => 1..=1,
+            JsonParse => 1..=1,
+            JsonStringify => 1..=2,
+            Spawn => 1..=1,
+            Await => 1..=1,
+            Join => 1..=1,
+            Sleep => 1..=1,
+            Now => 0..=0,
+            Channel => 1..=1,
+            ChannelSend => 2..=2,
+            Recv => 1..=1,
+            Close => 1..=1,
+            Select => 1..=2,
         }
     }
 }
 
 fn symbol() -> Rc<dyn Eval> {
     Rc::new(Custom::new("IntrinsicSymbol", |int| {
-        let symbol = int.gen_symbol();
+        let description = match int.pop_value() {
+            Value::Null => None,
+            Value::Str(s) => Some(s.to_string()),
+            _ => panic!("The description passed to symbol() must be a string."),
+        };
+        let symbol = int.gen_symbol(description);
         int.push_value(symbol);
     }))
 }
+
+fn symbol_for() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicSymbolFor", |int| {
+        let key = match int.pop_value() {
+            Value::Str(s) => s.to_string(),
+            _ => panic!("The key passed to symbol_for() must be a string."),
+        };
+        let symbol = int.global_symbol(key);
+        int.push_value(symbol);
+    }))
+}
+
+fn str() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicStr", |int| {
+        let value = int.pop_value();
+        if let Some(method) = value.well_known_method(WellKnownSymbol::Display) {
+            call_value(int, method, vec![value]);
+        } else {
+            let string = int.display_value(&value);
+            if let Err(err) = int.allocate(str_bytes(string.len())) {
+                return send_call_error(int, err);
+            }
+            int.push_value(Value::Str(Rc::new(string)));
+        }
+    }))
+}
+
+fn collect_cycles() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicCollectCycles", |int| {
+        let num_cleared = int.collect_cycles();
+        int.push_value(Value::Int(num_cleared as i64));
+    }))
+}
+
+fn debug_leaks() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicDebugLeaks", |int| {
+        int.report_leaks = true;
+        int.push_value(Value::Null);
+    }))
+}
+
+fn import() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicImport", |int| {
+        let path = match int.pop_value() {
+            Value::Str(s) => s,
+            _ => panic!("The path passed to import() must be a string."),
+        };
+        int.import_module(&path);
+    }))
+}
+
+fn json_parse() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicJsonParse", |int| {
+        let text = match int.pop_value() {
+            Value::Str(s) => s,
+            _ => panic!("The text passed to json.parse() must be a string."),
+        };
+        let result =
+            json::parse(&text).and_then(|value| int.allocate(value_bytes(&value)).map(|_| value));
+        match result {
+            Ok(value) => int.push_value(value),
+            Err(err) => send_call_error(int, err),
+        }
+    }))
+}
+
+fn json_stringify() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicJsonStringify", |int| {
+        let indent = match int.pop_value() {
+            Value::Null => None,
+            Value::Int(indent) if indent >= 0 => Some(indent as usize),
+            _ => panic!("The indent passed to json.stringify() must be a non-negative int."),
+        };
+        let value = int.pop_value();
+        let result = json::stringify(&value, indent)
+            .and_then(|text| int.allocate(str_bytes(text.len())).map(|_| text));
+        match result {
+            Ok(text) => int.push_value(Value::Str(Rc::new(text))),
+            Err(err) => send_call_error(int, err),
+        }
+    }))
+}
+
+fn spawn() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicSpawn", |int| {
+        let callable = int.pop_value();
+        let task = int.spawn_task(callable);
+        int.push_value(task);
+    }))
+}
+
+fn await_task() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicAwait", |int| {
+        let id = match int.pop_value() {
+            Value::Task(id) => id,
+            _ => panic!("The value passed to await() must be a task."),
+        };
+        int.wait_task(Wait::Task(id));
+    }))
+}
+
+fn join() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicJoin", |int| {
+        let ids: Vec<_> = match int.pop_value() {
+            Value::List(tasks) => tasks
+                .iter()
+                .map(|task| match task {
+                    Value::Task(id) => *id,
+                    _ => panic!("The list passed to join() must only contain tasks."),
+                })
+                .collect(),
+            _ => panic!("The value passed to join() must be a list of tasks."),
+        };
+        // the list of results is made once the tasks finish, but is charged for now
+        if let Err(err) = int.allocate(list_bytes(ids.len())) {
+            return send_call_error(int, err);
+        }
+        int.wait_task(Wait::Join(ids));
+    }))
+}
+
+fn sleep() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicSleep", |int| {
+        let ticks = match int.pop_value() {
+            Value::Int(ticks) if ticks >= 0 => ticks as u64,
+            _ => panic!("The time passed to sleep() must be a non-negative int."),
+        };
+        int.sleep_task(ticks);
+    }))
+}
+
+fn now() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicNow", |int| {
+        let clock = int.clock();
+        int.push_value(Value::Int(clock as i64));
+    }))
+}
+
+fn channel() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicChannel", |int| {
+        let capacity = match int.pop_value() {
+            Value::Int(capacity) if capacity > 0 => capacity as usize,
+            _ => panic!("The capacity passed to channel() must be a positive int."),
+        };
+        if let Err(err) = int.allocate(list_bytes(2) + channel_bytes()) {
+            return send_call_error(int, err);
+        }
+        let ends = int.channel(capacity);
+        int.push_value(ends);
+    }))
+}
+
+fn sender(value: Value, intrinsic: &str) -> ChannelId {
+    match value {
+        Value::Sender(id) => id,
+        _ => panic!("The channel passed to {}() must be a sender.", intrinsic),
+    }
+}
+
+fn receiver(value: Value, intrinsic: &str) -> ChannelId {
+    match value {
+        Value::Receiver(id) => id,
+        _ => panic!("The channel passed to {}() must be a receiver.", intrinsic),
+    }
+}
+
+fn channel_send() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicChannelSend", |int| {
+        let value = int.pop_value();
+        let id = sender(int.pop_value(), "send");
+        // only wait if the channel is full, or closed so that the error is sent the usual way
+        match int.try_send(id, value) {
+            None => int.push_value(Value::Null),
+            Some(value) => int.wait_task(Wait::Send(id, value)),
+        }
+    }))
+}
+
+fn recv() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicRecv", |int| {
+        let id = receiver(int.pop_value(), "recv");
+        int.wait_task(Wait::Recv(id));
+    }))
+}
+
+fn close() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicClose", |int| {
+        let id = sender(int.pop_value(), "close");
+        int.close_channel(id);
+        int.push_value(Value::Null);
+    }))
+}
+
+fn select() -> Rc<dyn Eval> {
+    Rc::new(Custom::new("IntrinsicSelect", |int| {
+        let timeout = match int.pop_value() {
+            Value::Null => None,
+            Value::Int(ticks) if ticks >= 0 => Some(int.clock() + ticks as u64),
+            _ => panic!("The timeout passed to select() must be a non-negative int."),
+        };
+        let ids = match int.pop_value() {
+            Value::List(receivers) => receivers
+                .iter()
+                .map(|value| receiver(value.clone(), "select"))
+                .collect(),
+            _ => panic!("The value passed to select() must be a list of receivers."),
+        };
+        int.wait_task(Wait::Select(ids, timeout));
+    }))
+}
@@ -0,0 +1,143 @@
+// Converting between JSON text and Kal values, for the `json.parse` and `json.stringify` intrinsics.
+//
+// JSON numbers which are integers become ints, and the rest become floats. Floats which are
+// NaN or infinite can't be written as JSON.
+// Object keys are written in sorted order, because Kal objects don't keep their order.
+
+use crate::interpreter::{Key, Value};
+use crate::Error;
+use serde::Serialize;
+use std::{collections::HashMap, rc::Rc};
+
+fn json_error(message: String, value: Value) -> Error {
+    Error::Runtime {
+        kind: "json".to_owned(),
+        message,
+        value,
+    }
+}
+
+pub fn parse(text: &str) -> Result<Value, Error> {
+    let json: serde_json::Value = serde_json::from_str(text).map_err(|err| {
+        json_error(
+            format!("Invalid JSON: {}", err),
+            Value::Str(Rc::new(text.to_owned())),
+        )
+    })?;
+    from_json(json)
+}
+
+pub fn from_json(json: serde_json::Value) -> Result<Value, Error> {
+    Ok(match json {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Bool(b),
+        serde_json::Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+            (Some(i), _) => Value::Int(i),
+            (None, Some(f)) => Value::Float(f),
+            (None, None) => {
+                return Err(json_error(
+                    format!("Can't parse the JSON number {}.", n),
+                    Value::Str(Rc::new(n.to_string())),
+                ))
+            }
+        },
+        serde_json::Value::String(s) => Value::Str(Rc::new(s)),
+        serde_json::Value::Array(elems) => Value::List(Rc::new(
+            elems.into_iter().map(from_json).collect::<Result<_, _>>()?,
+        )),
+        serde_json::Value::Object(entries) => {
+            let mut obj = HashMap::with_capacity(entries.len());
+            for (key, value) in entries {
+                obj.insert(Key::Str(key), from_json(value)?);
+            }
+            Value::Object(Rc::new(obj))
+        }
+    })
+}
+
+// Write a value as JSON, on one line or indented by `indent` spaces.
+pub fn stringify(value: &Value, indent: Option<usize>) -> Result<String, Error> {
+    let json = to_json(value)?;
+    let text = match indent {
+        None => serde_json::to_string(&json),
+        Some(indent) => {
+            let indent = " ".repeat(indent);
+            let formatter = serde_json::ser::PrettyFormatter::with_indent(indent.as_bytes());
+            let mut bytes = Vec::new();
+            let mut serializer = serde_json::Serializer::with_formatter(&mut bytes, formatter);
+            json.serialize(&mut serializer)
+                .map(|_| String::from_utf8(bytes).expect("serde_json always writes UTF-8."))
+        }
+    };
+    Ok(text.expect("Writing a JSON value to memory can't fail."))
+}
+
+pub fn to_json(value: &Value) -> Result<serde_json::Value, Error> {
+    Ok(match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Bool(b) => serde_json::Value::Bool(*b),
+        Value::Int(i) => serde_json::Value::Number((*i).into()),
+        Value::Float(f) => match serde_json::Number::from_f64(*f) {
+            Some(n) => serde_json::Value::Number(n),
+            None => {
+                return Err(json_error(
+                    format!("Can't convert {:?} to JSON.", f),
+                    value.clone(),
+                ))
+            }
+        },
+        Value::Str(s) => serde_json::Value::String(s.to_string()),
+        Value::List(list) => {
+            serde_json::Value::Array(list.iter().map(to_json).collect::<Result<_, _>>()?)
+        }
+        Value::Object(obj) => {
+            let mut entries = serde_json::Map::new();
+            for (key, value) in obj.iter() {
+                let key = match key {
+                    Key::Str(key) => key.clone(),
+                    _ => {
+                        return Err(json_error(
+                            format!(
+                                "Can't convert the object key {:?} to JSON, keys must be strings.",
+                                key
+                            ),
+                            Value::Object(obj.clone()),
+                        ))
+                    }
+                };
+                entries.insert(key, to_json(value)?);
+            }
+            serde_json::Value::Object(entries)
+        }
+        Value::Symbol(_) => {
+            return Err(json_error(
+                "Can't convert a symbol to JSON.".to_owned(),
+                value.clone(),
+            ))
+        }
+        Value::Closure(_) | Value::Intrinsic(_) | Value::NativeFunction(_) => {
+            return Err(json_error(
+                "Can't convert a function to JSON.".to_owned(),
+                value.clone(),
+            ))
+        }
+        Value::Effect(_) => {
+            return Err(json_error(
+                "Can't convert an effect to JSON.".to_owned(),
+                value.clone(),
+            ))
+        }
+        Value::Task(_) => {
+            return Err(json_error(
+                "Can't convert a task to JSON.".to_owned(),
+                value.clone(),
+            ))
+        }
+        Value::Sender(_) | Value::Receiver(_) => {
+            return Err(json_error(
+                "Can't convert a channel to JSON.".to_owned(),
+                value.clone(),
+            ))
+        }
+    })
+}
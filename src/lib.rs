@@ -0,0 +1,160 @@
+//! Kal is a dynamically typed language, where I/O, errors and generators are managed with effects.
+//!
+//! The simplest way to run Kal code is [`eval_source`]. An [`Interpreter`] can be used directly to
+//! run files, import modules, or grant capabilities to modules which declare a manifest.
+
+#[cfg(test)]
+mod tests;
+
+mod ast;
+mod convert;
+mod cycles;
+mod eval;
+mod eval_impls;
+mod interpreter;
+mod intrinsics;
+mod json;
+mod limits;
+mod replay;
+mod tasks;
+
+use std::{
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    rc::Rc,
+};
+
+use lalrpop_util::lalrpop_mod;
+lalrpop_mod!(
+    #[allow(clippy::all, unused_mut, unused_imports, dead_code)]
+    kal_grammar
+);
+
+pub use convert::{from_value, to_value, FromValue, IntoValue};
+pub use interpreter::{Interpreter, Key, RunStatus, SuspendedEffect, Value};
+pub use limits::Limits;
+
+use intrinsics::WellKnownSymbol;
+
+/// An error from parsing or running Kal code.
+#[derive(Debug)]
+pub enum Error {
+    /// The source code couldn't be parsed.
+    Parse { message: String },
+    /// The program sent the `error` effect without handling it.
+    Runtime {
+        kind: String,
+        message: String,
+        value: Value,
+    },
+    /// The program sent an effect other than `error` without handling it.
+    UnhandledEffect { symbol: String, value: Value },
+}
+
+impl Error {
+    /// An error for native functions to return, which Kal code can handle as the `error` effect.
+    pub fn runtime(kind: impl Into<String>, message: impl Into<String>) -> Self {
+        Error::Runtime {
+            kind: kind.into(),
+            message: message.into(),
+            value: Value::Null,
+        }
+    }
+
+    // The kind, message and value of the `error` effect for this error.
+    fn into_parts(self) -> (String, String, Value) {
+        match self {
+            Error::Parse { message } => ("parse".to_owned(), message, Value::Null),
+            Error::Runtime {
+                kind,
+                message,
+                value,
+            } => (kind, message, value),
+            Error::UnhandledEffect { symbol, value } => (
+                "unhandled_effect".to_owned(),
+                format!("Unhandled effect {}", symbol),
+                value,
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse { message } => write!(f, "Failed to parse: {}", message),
+            Error::Runtime { kind, message, .. } => write!(f, "{} error: {}", kind, message),
+            Error::UnhandledEffect { symbol, .. } => write!(f, "Unhandled effect {}", symbol),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A parsed Kal program.
+#[derive(Debug, Clone)]
+pub struct Program(Rc<ast::Block>);
+
+/// Parse a Kal program. The result can be run with [`Interpreter::eval`] or [`Interpreter::start`].
+pub fn parse(source: &str) -> Result<Program, Error> {
+    kal_grammar::BlockInnerParser::new()
+        .parse(source)
+        .map(Program)
+        .map_err(|err| Error::Parse {
+            message: err.to_string(),
+        })
+}
+
+/// Parse and run a Kal program in a new interpreter.
+///
+/// Effects which the program doesn't handle are returned as errors. Other problems, like type
+/// errors, panic inside the interpreter, and are returned as [`Error::Runtime`] with the kind
+/// `"panic"`.
+pub fn eval_source(source: &str) -> Result<Value, Error> {
+    let program = parse(source)?;
+    // the interpreter is thrown away if it panics, so it can't be seen in a broken state
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut interpreter = Interpreter::new();
+        let value = interpreter.eval(program)?;
+        check_unhandled_effect(&interpreter, value)
+    }));
+    result.unwrap_or_else(|payload| {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => match payload.downcast::<&str>() {
+                Ok(message) => message.to_string(),
+                Err(_) => "The interpreter panicked.".to_owned(),
+            },
+        };
+        Err(Error::runtime("panic", message))
+    })
+}
+
+fn check_unhandled_effect(interpreter: &Interpreter, value: Value) -> Result<Value, Error> {
+    let effect = match value {
+        Value::Effect(effect) => effect,
+        value => return Ok(value),
+    };
+    if effect.symbol != WellKnownSymbol::Error.id() {
+        return Err(Error::UnhandledEffect {
+            symbol: interpreter.display_value(&Value::Symbol(effect.symbol)),
+            value: effect.value.clone(),
+        });
+    }
+
+    // errors sent by the language are objects with a kind, message and value
+    let field = |name: &str| match &effect.value {
+        Value::Object(obj) => obj.get(&Key::Str(name.to_owned())).cloned(),
+        _ => None,
+    };
+    let field_string = |name: &str| match field(name) {
+        Some(Value::Str(s)) => s.to_string(),
+        Some(value) => interpreter.display_value(&value),
+        None => String::new(),
+    };
+    Err(Error::Runtime {
+        kind: field_string("kind"),
+        message: field_string("message"),
+        value: field("value").unwrap_or_else(|| effect.value.clone()),
+    })
+}
@@ -0,0 +1,127 @@
+use crate::interpreter::{Binding, Key, Scope, Value};
+use std::{collections::HashMap, collections::HashSet, mem::size_of, rc::Rc};
+
+// Untrusted programs can use up the host's memory by building huge lists or strings, eg. by
+// doubling a list with `[...list, ...list]` in a loop, or by recursing forever. When a limit is
+// reached the program is sent the `error` effect with the kind "resource_exhausted".
+//
+// Heap usage is only approximate. Creating a list, object or string adds its size to an upper
+// bound of what the program holds. When the bound goes over the limit, everything reachable from
+// the interpreter is measured to find out what is actually still held.
+
+pub const RESOURCE_EXHAUSTED: &str = "resource_exhausted";
+
+/// Limits on what a program can use. `None` means there is no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Values waiting on the value stack of a single context, eg. the elements of a list literal.
+    pub max_value_stack: Option<usize>,
+    /// How many function calls can be nested inside each other.
+    pub max_call_depth: Option<usize>,
+    /// Roughly how many bytes of lists, objects and strings can be held at once.
+    pub max_heap_bytes: Option<usize>,
+}
+
+pub fn list_bytes(len: usize) -> usize {
+    size_of::<Vec<Value>>() + len * size_of::<Value>()
+}
+
+pub fn object_bytes(len: usize) -> usize {
+    size_of::<HashMap<Key, Value>>() + len * (size_of::<Key>() + size_of::<Value>())
+}
+
+pub fn str_bytes(len: usize) -> usize {
+    size_of::<String>() + len
+}
+
+// The size of everything in a value that was made all at once outside of Kal code, eg. by
+// `json.parse` or a native function.
+pub fn value_bytes(value: &Value) -> usize {
+    let mut measure = HeapMeasure::default();
+    measure.value(value);
+    measure.finish()
+}
+
+enum Pending {
+    Value(Value),
+    Scope(Rc<Scope>),
+}
+
+// Adds up the size of the lists and objects reachable from some roots. Values shared
+// between several roots are only counted once.
+#[derive(Default)]
+pub struct HeapMeasure {
+    seen: HashSet<*const ()>,
+    pending: Vec<Pending>,
+    bytes: usize,
+}
+
+impl HeapMeasure {
+    pub fn value(&mut self, value: &Value) {
+        match value {
+            Value::List(_)
+            | Value::Object(_)
+            | Value::Str(_)
+            | Value::Closure(_)
+            | Value::Effect(_) => self.pending.push(Pending::Value(value.clone())),
+            _ => (),
+        }
+    }
+
+    // Count something which isn't a value, eg. a channel.
+    pub fn bytes(&mut self, bytes: usize) {
+        self.bytes += bytes;
+    }
+
+    pub fn scope(&mut self, scope: &Rc<Scope>) {
+        self.pending.push(Pending::Scope(scope.clone()));
+    }
+
+    // Visit everything reachable from the roots, returning the total size.
+    pub fn finish(mut self) -> usize {
+        while let Some(pending) = self.pending.pop() {
+            match pending {
+                Pending::Scope(scope) => {
+                    if !self.seen.insert(Rc::as_ptr(&scope) as *const ()) {
+                        continue;
+                    }
+                    for binding in scope.bindings.values() {
+                        match binding {
+                            Binding::Value(value) => self.value(value),
+                            Binding::Cell(cell) => self.value(&cell.borrow()),
+                        }
+                    }
+                    if let Some(parent) = &scope.parent {
+                        self.scope(parent);
+                    }
+                }
+                Pending::Value(Value::List(list)) => {
+                    if self.seen.insert(Rc::as_ptr(&list) as *const ()) {
+                        self.bytes += list_bytes(list.len());
+                        list.iter().for_each(|value| self.value(value));
+                    }
+                }
+                Pending::Value(Value::Object(obj)) => {
+                    if self.seen.insert(Rc::as_ptr(&obj) as *const ()) {
+                        self.bytes += object_bytes(obj.len());
+                        obj.values().for_each(|value| self.value(value));
+                    }
+                }
+                Pending::Value(Value::Str(s)) => {
+                    if self.seen.insert(Rc::as_ptr(&s) as *const ()) {
+                        self.bytes += str_bytes(s.len());
+                    }
+                }
+                Pending::Value(Value::Closure(closure)) => self.scope(&closure.parent_scope),
+                Pending::Value(Value::Effect(effect)) => {
+                    if self.seen.insert(Rc::as_ptr(&effect) as *const ()) {
+                        self.value(&effect.value);
+                        effect.ctx.measure_heap(&mut self);
+                    }
+                }
+                Pending::Value(_) => (),
+            }
+        }
+        self.bytes
+    }
+}
@@ -1,22 +1,10 @@
-#[cfg(test)]
-mod tests;
-
-mod ast;
-mod eval;
-mod eval_impls;
-mod interpreter;
-mod intrinsics;
-
 use std::path::PathBuf;
 
-use lalrpop_util::lalrpop_mod;
-lalrpop_mod!(#[allow(clippy::all)] pub kal_grammar);
-
-use interpreter::Interpreter;
+use kal::Interpreter;
 
 use clap::Parser;
 
-/// Simple program to greet a person
+/// Run a Kal program
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -24,18 +12,14 @@ struct Args {
 }
 
 fn main() {
-
     let args = Args::parse();
-    let borrowed_path = args.filename.as_path();
-    let file = std::fs::read_to_string(borrowed_path).unwrap_or_else(|err| panic!("Failed to read file {:}. Error: {:?}", borrowed_path.to_string_lossy(), err));
-
-    let ast = kal_grammar::BlockInnerParser::new()
-        .parse(file.as_str())
-        .unwrap_or_else(|err| panic!("Failed to parse file, {:?}", err));
-
     let mut interpreter = Interpreter::new();
 
-    let result = interpreter.eval(ast);
-
-    println!("{:#?}", result);
+    match interpreter.start_file(args.filename.as_path()) {
+        Ok(result) => println!("{}", interpreter.display_value(&result)),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,72 @@
+use crate::interpreter::Value;
+use crate::json::{from_json, to_json};
+use crate::Error;
+
+// A suspended program can't be saved directly, because its eval stacks hold Rust closures.
+// Instead a replay log is the source of the program and the values that the host answered its
+// effects with. Kal programs are deterministic, so replaying the log runs the program again
+// from the start, with the same answers, until it is suspended at the same effect again.
+//
+// This has limits which a real snapshot of the interpreter wouldn't:
+// - Only programs started with `Interpreter::start_recorded` are logged, because the log needs
+//   their source. Programs run with `start`, `eval` or `start_file` can't be logged.
+// - The log is JSON, so every answer has to be a value that can be converted to JSON. Answering
+//   with a symbol, function or channel makes writing the log fail.
+// - Everything the program did before it was suspended is done again, including calls to
+//   native functions. They have to be registered the same way before replaying, and should be
+//   deterministic and safe to repeat.
+
+pub struct ReplayLog {
+    pub source: String,
+    pub answers: Vec<Value>,
+}
+
+pub fn replay_error(message: impl Into<String>) -> Error {
+    Error::runtime("replay", message)
+}
+
+impl ReplayLog {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut answers = Vec::with_capacity(self.answers.len());
+        for (index, answer) in self.answers.iter().enumerate() {
+            let answer = to_json(answer).map_err(|err| {
+                replay_error(format!(
+                    "Answer {} to the program's effects can't be logged: {}",
+                    index, err
+                ))
+            })?;
+            answers.push(answer);
+        }
+        let json = serde_json::json!({
+            "source": self.source,
+            "answers": answers,
+        });
+        Ok(serde_json::to_vec(&json).expect("Writing a JSON value to memory can't fail."))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ReplayLog, Error> {
+        let json: serde_json::Value = serde_json::from_slice(bytes)
+            .map_err(|err| replay_error(format!("Invalid replay log: {}", err)))?;
+        let source = match json.get("source") {
+            Some(serde_json::Value::String(source)) => source.clone(),
+            _ => {
+                return Err(replay_error(
+                    "Invalid replay log: the source must be a string.",
+                ))
+            }
+        };
+        let answers = match json.get("answers") {
+            Some(serde_json::Value::Array(answers)) => answers
+                .iter()
+                .cloned()
+                .map(from_json)
+                .collect::<Result<_, _>>()?,
+            _ => {
+                return Err(replay_error(
+                    "Invalid replay log: the answers must be a list.",
+                ))
+            }
+        };
+        Ok(ReplayLog { source, answers })
+    }
+}
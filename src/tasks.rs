@@ -0,0 +1,341 @@
+use crate::interpreter::{FunctionContext, Value};
+use crate::limits::HeapMeasure;
+use crate::Error;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    mem::size_of,
+    path::PathBuf,
+    rc::Rc,
+};
+
+// Kal programs can run several tasks, which take turns on one thread. `spawn(f)` starts a task
+// which calls f, and a task only gives up its turn when it waits for something, with
+// `await(task)`, `join(tasks)` or `sleep(ticks)`. Each task has its own `fn_context_stack`, so
+// switching tasks swaps the whole stack out of the interpreter. Tasks that are ready to run
+// take turns in the order they started waiting.
+//
+// Tasks communicate through channels, made by `channel(capacity)`. Sending to a full channel,
+// receiving from an empty one, or selecting between several channels are more ways to wait.
+// Closing a channel lets receivers finish once they have taken everything that was sent.
+// A closed channel is freed once it is empty, since it can't change after that. Its sender
+// and receiver can still be used, and act like any other closed, empty channel.
+//
+// The clock is virtual. Time only passes when no task is ready to run, and then jumps straight
+// to when the next sleeping task wakes up, so programs which sleep are still deterministic.
+
+pub type TaskId = u64;
+pub type ChannelId = u64;
+
+// the program itself
+pub const MAIN_TASK: TaskId = 0;
+
+pub enum Wait {
+    // a spawned task that hasn't run yet
+    Start,
+    // until the clock reaches this time
+    Sleep(u64),
+    // until the task finishes, to get its value
+    Task(TaskId),
+    // until all of the tasks finish, to get a list of their values
+    Join(Vec<TaskId>),
+    // until the channel has room for the value
+    Send(ChannelId, Value),
+    // until the channel has a value
+    Recv(ChannelId),
+    // like Recv, but gets `[value]`, or null once the channel is closed, for `for` loops
+    Next(ChannelId),
+    // until any of the channels has a value, to get `[index, value]`, or null after the time
+    Select(Vec<ChannelId>, Option<u64>),
+    // until another task has finished importing the module
+    Module(PathBuf),
+}
+
+struct Channel {
+    capacity: usize,
+    buffer: VecDeque<Value>,
+    closed: bool,
+}
+
+struct Task {
+    id: TaskId,
+    stack: Vec<FunctionContext>,
+    wait: Wait,
+}
+
+pub struct Scheduler {
+    pub clock: u64,
+    pub current: TaskId,
+    next_id: TaskId,
+    // every task except the current one which hasn't finished
+    waiting: VecDeque<Task>,
+    // The values of tasks which have finished. A task can be awaited any number of times, so
+    // these are kept until `Interpreter::start` replaces the scheduler, or for the interpreter's
+    // lifetime otherwise. They count towards the heap limit like any other value it holds.
+    finished: HashMap<TaskId, Value>,
+    // the channels which are open, or closed but still have values to receive
+    channels: HashMap<ChannelId, Channel>,
+    next_channel_id: ChannelId,
+    loaded_modules: HashSet<PathBuf>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler {
+            clock: 0,
+            current: MAIN_TASK,
+            next_id: MAIN_TASK + 1,
+            waiting: VecDeque::new(),
+            finished: HashMap::new(),
+            channels: HashMap::new(),
+            next_channel_id: 0,
+            loaded_modules: HashSet::new(),
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn spawn(&mut self, stack: Vec<FunctionContext>) -> TaskId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.waiting.push_back(Task {
+            id,
+            stack,
+            wait: Wait::Start,
+        });
+        id
+    }
+
+    // Put the current task to the back of the queue.
+    pub fn park(&mut self, stack: Vec<FunctionContext>, wait: Wait) {
+        self.waiting.push_back(Task {
+            id: self.current,
+            stack,
+            wait,
+        });
+    }
+
+    pub fn finish(&mut self, value: Value) {
+        self.finished.insert(self.current, value);
+    }
+
+    pub fn module_loaded(&mut self, path: PathBuf) {
+        self.loaded_modules.insert(path);
+    }
+
+    pub fn channel(&mut self, capacity: usize) -> ChannelId {
+        let id = self.next_channel_id;
+        self.next_channel_id += 1;
+        self.channels.insert(
+            id,
+            Channel {
+                capacity,
+                buffer: VecDeque::new(),
+                closed: false,
+            },
+        );
+        id
+    }
+
+    pub fn close(&mut self, id: ChannelId) {
+        if let Some(channel) = self.channel_mut(id) {
+            channel.closed = true;
+        }
+        self.free_if_finished(id);
+    }
+
+    // Put a value straight into a channel which has room for it, without waiting. Gives the
+    // value back if the channel is full or closed.
+    pub fn try_send(&mut self, id: ChannelId, value: Value) -> Option<Value> {
+        match self.channel_mut(id) {
+            Some(channel) if !channel.closed && channel.buffer.len() < channel.capacity => {
+                channel.buffer.push_back(value);
+                None
+            }
+            _ => Some(value),
+        }
+    }
+
+    // `None` if the channel was closed and has been freed.
+    fn channel_ref(&self, id: ChannelId) -> Option<&Channel> {
+        assert!(id < self.next_channel_id, "There is no channel {}.", id);
+        self.channels.get(&id)
+    }
+
+    fn channel_mut(&mut self, id: ChannelId) -> Option<&mut Channel> {
+        assert!(id < self.next_channel_id, "There is no channel {}.", id);
+        self.channels.get_mut(&id)
+    }
+
+    fn is_closed(&self, id: ChannelId) -> bool {
+        self.channel_ref(id).is_none_or(|channel| channel.closed)
+    }
+
+    fn receive(&mut self, id: ChannelId) -> Option<Value> {
+        let value = self.channel_mut(id)?.buffer.pop_front();
+        self.free_if_finished(id);
+        value
+    }
+
+    fn free_if_finished(&mut self, id: ChannelId) {
+        if let Some(channel) = self.channels.get(&id) {
+            if channel.closed && channel.buffer.is_empty() {
+                self.channels.remove(&id);
+            }
+        }
+    }
+
+    // Switch to the first task which is ready to run, returning its stack and the result of
+    // what it was waiting for, if anything. If every task is stuck, the main task gets an
+    // error with the kind "deadlock".
+    pub fn next_ready(&mut self) -> (Vec<FunctionContext>, Option<Result<Value, Error>>) {
+        loop {
+            let ready = self
+                .waiting
+                .iter()
+                .position(|task| self.is_ready(&task.wait));
+            if let Some(index) = ready {
+                let task = self.waiting.remove(index).unwrap();
+                self.current = task.id;
+                let result = self.wait_result(task.wait);
+                return (task.stack, result);
+            }
+
+            let next_wake = self
+                .waiting
+                .iter()
+                .filter_map(|task| match task.wait {
+                    Wait::Sleep(time) | Wait::Select(_, Some(time)) => Some(time),
+                    _ => None,
+                })
+                .min();
+            if let Some(time) = next_wake {
+                self.clock = time;
+                continue;
+            }
+
+            // Nothing will ever be ready, so wake the program itself up with an error instead.
+            let index = self
+                .waiting
+                .iter()
+                .position(|task| task.id == MAIN_TASK)
+                .unwrap_or(0);
+            let task = self
+                .waiting
+                .remove(index)
+                .expect("The current task was just parked.");
+            self.current = task.id;
+            let err = Error::runtime(
+                "deadlock",
+                "Deadlock: every task is waiting for something that will never happen.",
+            );
+            return (task.stack, Some(Err(err)));
+        }
+    }
+
+    fn is_ready(&self, wait: &Wait) -> bool {
+        match wait {
+            Wait::Start => true,
+            Wait::Sleep(time) => *time <= self.clock,
+            Wait::Task(id) => self.finished.contains_key(id),
+            Wait::Join(ids) => ids.iter().all(|id| self.finished.contains_key(id)),
+            Wait::Send(id, _) => self
+                .channel_ref(*id)
+                .is_none_or(|channel| channel.closed || channel.buffer.len() < channel.capacity),
+            Wait::Recv(id) | Wait::Next(id) => self
+                .channel_ref(*id)
+                .is_none_or(|channel| channel.closed || !channel.buffer.is_empty()),
+            Wait::Select(ids, deadline) => {
+                let timed_out = deadline.is_some_and(|deadline| deadline <= self.clock);
+                let has_value = ids.iter().any(|id| {
+                    self.channel_ref(*id)
+                        .is_some_and(|channel| !channel.buffer.is_empty())
+                });
+                let all_closed = ids.iter().all(|id| self.is_closed(*id));
+                timed_out || has_value || all_closed
+            }
+            Wait::Module(path) => self.loaded_modules.contains(path),
+        }
+    }
+
+    // The value a task continues with once its wait is over, or the error it gets instead.
+    fn wait_result(&mut self, wait: Wait) -> Option<Result<Value, Error>> {
+        let result = match wait {
+            Wait::Start => return None,
+            Wait::Sleep(_) | Wait::Module(_) => Ok(Value::Null),
+            Wait::Task(id) => Ok(self.finished[&id].clone()),
+            Wait::Join(ids) => Ok(Value::List(Rc::new(
+                ids.iter().map(|id| self.finished[id].clone()).collect(),
+            ))),
+            Wait::Send(id, value) => match self.try_send(id, value) {
+                None => Ok(Value::Null),
+                Some(_) => Err(channel_closed(id, "send to")),
+            },
+            Wait::Recv(id) => match self.receive(id) {
+                Some(value) => Ok(value),
+                None => Err(channel_closed(id, "receive from")),
+            },
+            Wait::Next(id) => match self.receive(id) {
+                Some(value) => Ok(Value::List(Rc::new(vec![value]))),
+                None => Ok(Value::Null),
+            },
+            Wait::Select(ids, _) => {
+                let ready = ids.iter().enumerate().find_map(|(index, id)| {
+                    let value = self.receive(*id)?;
+                    Some(Value::List(Rc::new(vec![Value::Int(index as i64), value])))
+                });
+                match ready {
+                    Some(value) => Ok(value),
+                    // all of the channels are closed, unless the timeout passed first
+                    None if ids.iter().all(|id| self.is_closed(*id)) && !ids.is_empty() => {
+                        Err(Error::runtime(
+                            "channel_closed",
+                            "Can't select from channels which are all closed.",
+                        ))
+                    }
+                    None => Ok(Value::Null),
+                }
+            }
+        };
+        Some(result)
+    }
+
+    // Tasks are only known by the interpreter that spawned them.
+    pub fn check_task(&self, id: TaskId) -> Result<(), Error> {
+        if id < self.next_id {
+            Ok(())
+        } else {
+            Err(Error::runtime(
+                "task",
+                format!("There is no task {} to wait for.", id),
+            ))
+        }
+    }
+
+    pub fn measure_heap(&self, measure: &mut HeapMeasure) {
+        for task in self.waiting.iter() {
+            task.stack.iter().for_each(|ctx| ctx.measure_heap(measure));
+            if let Wait::Send(_, value) = &task.wait {
+                measure.value(value);
+            }
+        }
+        self.finished
+            .values()
+            .for_each(|value| measure.value(value));
+        for channel in self.channels.values() {
+            measure.bytes(channel_bytes());
+            channel.buffer.iter().for_each(|value| measure.value(value));
+        }
+    }
+}
+
+// The size of a channel, not counting the values in it.
+pub fn channel_bytes() -> usize {
+    size_of::<(ChannelId, Channel)>()
+}
+
+fn channel_closed(id: ChannelId, action: &str) -> Error {
+    Error::runtime(
+        "channel_closed",
+        format!("Can't {} channel {}, it is closed.", action, id),
+    )
+}
@@ -1,16 +1,31 @@
-use crate::interpreter::{Interpreter, Key, Value::{self, *}};
-use std::{collections::HashMap, rc::Rc};
+use crate::interpreter::{
+    Interpreter, Key, RunStatus, SuspendedEffect,
+    Value::{self, *},
+};
+use crate::limits::Limits;
+use std::{collections::HashMap, path::Path, rc::Rc};
 
 #[allow(dead_code)]
 fn eval_file(path: &str) -> Value {
-    let text =
-        std::fs::read_to_string(path).unwrap_or_else(|_| panic!("Could not read file {:?}", path));
-    let ast = crate::kal_grammar::BlockInnerParser::new()
-        .parse(&text)
-        .unwrap_or_else(|_| panic!("Failed to parse file {:?}.", path));
     let mut runtime = Interpreter::new();
 
-    runtime.eval(ast)
+    runtime.eval_file(Path::new(path)).unwrap()
+}
+
+// Evaluate a file, then get the names of the `let mut` bindings it leaked in reference cycles.
+// The result is checked first, since it can keep bindings alive.
+fn eval_file_leaks(path: &str, expected: Value) -> Vec<String> {
+    let mut runtime = Interpreter::new();
+
+    let val = runtime.eval_file(Path::new(path)).unwrap();
+    assert!(
+        val == expected,
+        "Assertion failed: got {:?}, expected {:?}.",
+        val,
+        expected
+    );
+    drop(val);
+    runtime.leaked_bindings()
 }
 
 macro_rules! test {
@@ -38,22 +53,59 @@ macro_rules! test_error {
         #[test]
         #[should_panic]
         pub fn $test_name() {
-            let val = eval_file(&format!("tests/{}.error.kal", stringify!($test_name)));
+            eval_file(&format!("tests/{}.error.kal", stringify!($test_name)));
+        }
+    };
+    {$test_name:ident, $expected_message:literal} => {
+        #[test]
+        #[should_panic(expected = $expected_message)]
+        pub fn $test_name() {
+            eval_file(&format!("tests/{}.error.kal", stringify!($test_name)));
+        }
+    };
+}
+
+macro_rules! test_leaks {
+    {$test_name:ident, $expected_val:expr, [$($leaked:literal),*]} => {
+        #[test]
+        pub fn $test_name() {
+            let leaked = eval_file_leaks(&format!("tests/{}.kal", stringify!($test_name)), $expected_val);
+            let expected_leaked: Vec<&str> = vec![$($leaked),*];
+            assert_eq!(leaked, expected_leaked);
         }
     };
 }
 
+// Check that a result is a runtime error of the given kind, with a message containing `message`.
+fn assert_runtime_error<T: std::fmt::Debug>(
+    result: Result<T, crate::Error>,
+    kind: &str,
+    message: &str,
+) {
+    match result {
+        Err(crate::Error::Runtime {
+            kind: actual_kind,
+            message: actual_message,
+            ..
+        }) if actual_kind == kind => assert!(
+            actual_message.contains(message),
+            "Expected the message to contain {:?}, got {:?}.",
+            message,
+            actual_message
+        ),
+        result => panic!("Expected a {} error, got {:?}.", kind, result),
+    }
+}
+
 #[cfg(not(debug_assertions))]
 #[test]
 fn big_file() {
     let size = 4_000_000_i64;
     let let_statements = "let num=num+1;".repeat(size as usize);
     let text = format!("let num = 0; {} num", let_statements);
-    let ast = crate::kal_grammar::BlockInnerParser::new()
-        .parse(&text)
-        .unwrap();
+    let program = crate::parse(&text).unwrap();
     let mut runtime = Interpreter::new();
-    let val = runtime.eval(ast);
+    let val = runtime.eval(program).unwrap();
     assert!(val == Int(size));
 }
 
@@ -72,11 +124,11 @@ test! { fn_chained, Int(23) }
 test! { fn_object_empty, Object(Rc::new(HashMap::new())) }
 
 test! { fn_object,
-    {
-        let mut obj = HashMap::new();
-        obj.insert(Key::Str("cat".to_owned()), Int(1));
-        Object(Rc::new(obj))
-    } }
+{
+    let mut obj = HashMap::new();
+    obj.insert(Key::Str("cat".to_owned()), Int(1));
+    Object(Rc::new(obj))
+} }
 
 test! { fn_null, Null }
 test! { fn_named, Bool(true) }
@@ -96,6 +148,29 @@ test! { fibonacci, List(
     ]))
 }
 
+test! { keyword_args, List(Rc::new(vec![
+    List(Rc::new(vec![Int(1), Int(2)])),
+    List(Rc::new(vec![Int(4), Int(3)])),
+    List(Rc::new(vec![Int(5), Int(0)])),
+    List(Rc::new(vec![Str(Rc::new("a".to_owned())), Int(80), Object(Rc::new(HashMap::new()))])),
+    List(Rc::new(vec![Str(Rc::new("b".to_owned())), Int(8080), Object(Rc::new(HashMap::from([(Key::Str("tls".to_owned()), Bool(true))])))])),
+    Int(12),
+    Int(9),
+])) }
+test_error! { keyword_args_missing, "fn point is missing the keyword argument x" }
+test_error! { keyword_args_unexpected, "fn point got an unexpected keyword argument z" }
+test_error! { keyword_args_not_accepted, "fn add doesn't take keyword arguments, but was called with b" }
+test_error! { keyword_args_arity, "Must call fn area with exactly 2 params" }
+test_error! { keyword_args_positional, "fn area can only take h by position, because it is before the * in its parameters" }
+
+test! { return_from_fn, List(Rc::new(vec![Bool(true), Bool(false), Int(5), Null])) }
+
+test! { pipeline, List(Rc::new(vec![Int(6), Int(7), List(Rc::new(vec![Int(2), Int(4), Int(6)])), Bool(true)])) }
+
+test! { method_call, List(Rc::new(vec![Int(48), Int(13)])) }
+
+test! { method_call_intrinsic, List(Rc::new(vec![Int(1), Str(Rc::new("x".to_owned())), Int(5), Int(3)])) }
+
 test! { if_true, Int(71) }
 
 test! { if_false, Int(72) }
@@ -106,6 +181,10 @@ test! { if_else_if, Int(77) }
 
 test! { if_without_else, Null }
 
+test! { if_let, List(Rc::new(vec![Int(3), Int(5), Int(0), Int(0), Int(100), Str(Rc::new("kal".to_owned())), Str(Rc::new("anonymous".to_owned())), Str(Rc::new("not an object".to_owned()))])) }
+test! { let_else, List(Rc::new(vec![Int(8), Null, Null])) }
+test_error! { let_else_finish }
+
 test! { comparison_true, Bool(true) }
 
 test! { comparison_false, Bool(false) }
@@ -133,6 +212,10 @@ test! { object_nested, Int(22) }
 
 test! { object_spread, List(Rc::new(vec![Int(15), Int(20), Int(99)])) }
 
+test! { object_computed_keys, List(Rc::new(vec![Int(1), Str(Rc::new("x".to_owned())), Int(2), Int(3), Int(4), Int(5), Int(5)])) }
+
+test! { object_index_assign, List(Rc::new(vec![Int(10), Int(22)])) }
+
 test! { boolean_and, Bool(false) }
 
 test! { boolean_or, Bool(true) }
@@ -147,6 +230,33 @@ test! { symbol_as_value, Symbol(1) } // second symbol is always 1
 
 test! { symbol_equality, Bool(true) }
 
+test! { symbol_description,
+    List(Rc::new(
+        ["symbol(\"request\")", "symbol(1)", "symbol(\"yield\")", "{ [symbol(\"request\")]: 1 }"]
+            .iter()
+            .map(|s| Str(Rc::new(s.to_string())))
+            .collect()
+    ))
+}
+
+test_error! { symbol_description_missing_key, "The object does not contain the key symbol(\"other\")" }
+test_error! { symbol_description_comparison, "Cannot apply Less to symbol(\"request\") and 1" }
+
+test! { symbol_global, List(Rc::new(vec![Bool(true), Bool(true), Bool(true), Bool(true)])) }
+
+test! { symbols_operators, List(Rc::new(vec![Int(7), Int(10), Bool(true), Bool(true), Bool(true), Bool(true)])) }
+
+test! { symbols_index_call, List(Rc::new(vec![Int(49), Int(1), Int(5)])) }
+
+test! { symbols_display,
+    List(Rc::new(
+        ["a point", "12", "str", "[1, \"two\", null]", "{ a: [], b: true }"]
+            .iter()
+            .map(|s| Str(Rc::new(s.to_string())))
+            .collect()
+    ))
+}
+
 test! { trailing_commas, Int(2) }
 
 test! { list, List(Rc::new(vec![Int(1), Int(2), Int(3)])) }
@@ -161,6 +271,8 @@ test! { list_spread, List(Rc::new(vec![Int(1), Int(2), Int(3), Int(4), Int(5), I
 
 test! { int, Int(5) }
 
+test! { string, List(Rc::new(vec![Bool(true), Bool(true), Bool(true), Bool(true)])) }
+
 test! { int_negative, Int(-5) }
 
 test! { num_addition, Int(8) }
@@ -187,6 +299,23 @@ test! { mut_object_access, Bool(true) }
 
 test! { mut_deep, Int(3) }
 
+test! { mut_shadow, Int(6) }
+test! { mut_pattern, List(Rc::new(vec![Int(10), Int(22)])) }
+test_error! { mut_immutable, "Cannot assign to a, because it was not declared with `let mut`" }
+test_error! { mut_immutable_property, "Cannot assign to a, because it was not declared with `let mut`" }
+test_error! { mut_shadow_immutable, "Cannot assign to a, because it was not declared with `let mut`" }
+test! { mut_captured, List(Rc::new(vec![Int(2), Int(21)])) }
+test! { mut_captured_counter, List(Rc::new(vec![Int(2), Int(1)])) }
+
+test_error! { mut_captured_immutable, "Cannot assign to n, because it was not declared with `let mut`" }
+
+test_leaks! { leaks_generator, List(Rc::new(vec![Int(2), Int(7)])), [] }
+test_leaks! { leaks_list_mutation, List(Rc::new(vec![Int(0), Int(1), Int(4), Int(9)])), [] }
+test_leaks! { leaks_if_mutation, Int(3), [] }
+test_leaks! { leaks_counter, Int(2), [] }
+test_leaks! { leaks_cycle, Null, ["var"] }
+test_leaks! { leaks_cycle_collected, List(Rc::new(vec![Int(2), Int(1)])), [] }
+
 test! { handle_continue, Int(9) }
 
 test! { handle_multiple_continue, Int(81) }
@@ -199,6 +328,9 @@ test! { handle_nested_continue, Int(20) }
 
 test! { handle_two_effect_types, Int(25) }
 
+test! { handle_patterns, List(Rc::new(vec![Str(Rc::new("a.txt".to_owned())), Str(Rc::new("no write access".to_owned())), Int(3)])) }
+test! { handle_patterns_passthrough, List(Rc::new(vec![Str(Rc::new("inner".to_owned())), Str(Rc::new("outer".to_owned()))])) }
+
 #[test]
 fn handle_implicit() {
     let val = eval_file("tests/handle_implicit.kal");
@@ -235,11 +367,18 @@ test! { loop_yield, Int(8) }
 
 test! { loop_continue, Int(5) }
 
+test! { for_list, Int(10) }
+
+test! { for_generator, List(Rc::new(vec![Int(10), Int(7)])) }
+
+test! { for_iter, List(Rc::new(vec![Int(0), Int(10), Int(20), Int(30)])) }
+
+test! { for_break, Int(300) }
+
 test! { loop_collect, List(Rc::new(vec![Int(0), Int(1), Int(2), Int(3), Int(4)])) }
 
 test! { expression_as_statement, Int(2) }
 
-
 test! { pattern_fn_spread_last, Bool(true) }
 test! { pattern_fn_spread_nameless, Bool(true) }
 test! { pattern_fn_spread_only, Bool(true) }
@@ -259,3 +398,823 @@ test! { pattern_let_object_spread_nameless, Bool(true) }
 test! { pattern_let_object_spread_nameless_only, Null }
 test! { pattern_let_object_nested, Bool(true) }
 test! { pattern_let_object_wildcard, Bool(true) }
+test! { pattern_let_object_keyed, Bool(true) }
+test! { pattern_fn_object_keyed, Int(77) }
+test! { pattern_let_literal, Int(5) }
+test_error! { pattern_let_literal_mismatch }
+test! { pattern_let_object_rename, List(Rc::new(vec![Int(1), Int(2), Int(3), Int(4)])) }
+test! { pattern_let_object_default, List(Rc::new(vec![Str(Rc::new("localhost".to_owned())), Int(8080), Bool(true), Str(Rc::new("/".to_owned()))])) }
+test! { pattern_let_default_lazy, List(Rc::new(vec![Int(1), Int(2), Int(3)])) }
+test! { pattern_fn_default, List(Rc::new(vec![
+    List(Rc::new(vec![Int(1), Int(2)])),
+    List(Rc::new(vec![Int(1), Int(5)])),
+    List(Rc::new(vec![Int(1), Int(2), List(Rc::new(vec![])), Int(3)])),
+    List(Rc::new(vec![Int(1), Int(3), List(Rc::new(vec![])), Int(4)])),
+    List(Rc::new(vec![Int(1), Int(3), List(Rc::new(vec![Int(4), Int(5)])), Int(6)])),
+])) }
+test_error! { pattern_fn_default_too_few }
+
+test! { match_patterns, List(Rc::new(vec![
+    Str(Rc::new("zero".to_owned())),
+    Str(Rc::new("minus one".to_owned())),
+    List(Rc::new(vec![Int(1), Int(2)])),
+    Int(7),
+    Str(Rc::new("big circle".to_owned())),
+    Int(5),
+    Str(Rc::new("other".to_owned())),
+    Str(Rc::new("null".to_owned())),
+    Str(Rc::new("greeting".to_owned())),
+    Str(Rc::new("other".to_owned())),
+])) }
+test! { match_symbol, List(Rc::new(vec![Str(Rc::new("red".to_owned())), Str(Rc::new("green".to_owned())), Int(1)])) }
+test! { match_no_match, List(Rc::new(vec![Int(5), Str(Rc::new("no_match".to_owned())), Int(10)])) }
+
+test! { json_parse, List(Rc::new(vec![
+    Str(Rc::new("kal".to_owned())),
+    Int(443),
+    Bool(true),
+    Null,
+    Str(Rc::new("json".to_owned())),
+    Float(3.0),
+    Bool(true),
+])) }
+test! { json_stringify, List(Rc::new(vec![
+    Str(Rc::new(r#"{"a":{"yes":true},"b":[1,"two",null]}"#.to_owned())),
+    Str(Rc::new("{\n  \"a\": {\n    \"yes\": true\n  },\n  \"b\": [\n    1,\n    \"two\",\n    null\n  ]\n}".to_owned())),
+    Str(Rc::new("Can't convert a symbol to JSON.".to_owned())),
+    Str(Rc::new("Can't convert a function to JSON.".to_owned())),
+    Str(Rc::new("Can't convert the object key Int(1) to JSON, keys must be strings.".to_owned())),
+])) }
+
+test! { import_module, List(Rc::new(vec![Int(8), Int(16), Bool(true), Bool(true)])) }
+test_error! { import_cycle, "Import cycle" }
+test! { import_effect, List(Rc::new(vec![Int(10), Int(10), Int(1)])) }
+test! { import_tasks, Bool(true) }
+test! { import_missing, Str(Rc::new("module".to_owned())) }
+
+#[test]
+fn eval_file_errors() {
+    let mut runtime = Interpreter::new();
+    assert_runtime_error(
+        runtime.eval_file(Path::new("tests/modules/missing.kal")),
+        "module",
+        "Could not find module",
+    );
+    match runtime.eval_file(Path::new("tests/modules/bad_syntax.kal")) {
+        Err(crate::Error::Parse { message }) => {
+            assert!(message.contains("bad_syntax.kal"), "{}", message)
+        }
+        result => panic!("Expected a parse error, got {:?}.", result),
+    }
+    assert_runtime_error(
+        runtime.start_file(Path::new("tests/modules/no_main.kal")),
+        "manifest",
+        "has no main function",
+    );
+}
+
+#[test]
+fn eval_source_value() {
+    let val = crate::eval_source("let a = 1; a + 2").unwrap();
+    assert!(val == Int(3));
+}
+
+#[test]
+fn eval_source_parse_error() {
+    let err = crate::eval_source("let = 1").unwrap_err();
+    assert!(
+        matches!(err, crate::Error::Parse { .. }),
+        "Expected a parse error, got {:?}.",
+        err
+    );
+}
+
+#[test]
+fn eval_source_runtime_error() {
+    match crate::eval_source("match 3 { 1 => 2 }") {
+        Err(crate::Error::Runtime { kind, value, .. }) => {
+            assert_eq!(kind, "no_match");
+            assert!(value == Int(3));
+        }
+        other => panic!("Expected a runtime error, got {:?}.", other),
+    }
+}
+
+#[test]
+fn eval_source_unhandled_effect() {
+    match crate::eval_source("let ping = symbol(\"ping\"); send ping with 1") {
+        Err(crate::Error::UnhandledEffect { symbol, value }) => {
+            assert_eq!(symbol, "symbol(\"ping\")");
+            assert!(value == Int(1));
+        }
+        other => panic!("Expected an unhandled effect, got {:?}.", other),
+    }
+}
+
+#[test]
+fn eval_source_type_error() {
+    assert_runtime_error(
+        crate::eval_source("1 + true"),
+        "panic",
+        "right side not a number",
+    );
+}
+
+fn native_runtime() -> Interpreter {
+    let mut runtime = Interpreter::new();
+    runtime.register_fn("sum", |args| {
+        let mut total = 0;
+        for arg in args {
+            match arg {
+                Int(n) => total += n,
+                _ => return Err(crate::Error::runtime("type", "sum only takes ints")),
+            }
+        }
+        Ok(Int(total))
+    });
+    runtime.register_fn("check", |_args| {
+        Err(crate::Error::runtime("invalid", "check failed"))
+    });
+    runtime.register_fn_with_interpreter("apply_twice", |int, args| {
+        let once = int.call(args[0].clone(), vec![args[1].clone()])?;
+        int.call(args[0].clone(), vec![once])
+    });
+    runtime
+}
+
+#[test]
+fn native_fn() {
+    let val = native_runtime()
+        .eval_file(Path::new("tests/native_fn.kal"))
+        .unwrap();
+    let expected = List(Rc::new(vec![
+        Int(6),
+        Int(20),
+        Str(Rc::new("sum".to_owned())),
+        Bool(true),
+    ]));
+    assert!(
+        val == expected,
+        "Assertion failed: got {:?}, expected {:?}.",
+        val,
+        expected
+    );
+}
+
+#[test]
+fn native_fn_spread() {
+    let val = native_runtime()
+        .eval_file(Path::new("tests/native_fn_spread.kal"))
+        .unwrap();
+    let xs = List(Rc::new(vec![Int(1), Int(2), Int(3)]));
+    let expected = List(Rc::new(vec![Int(6), Int(6), Int(10), xs]));
+    assert!(
+        val == expected,
+        "Assertion failed: got {:?}, expected {:?}.",
+        val,
+        expected
+    );
+}
+
+#[test]
+fn native_fn_error() {
+    let val = native_runtime()
+        .eval_file(Path::new("tests/native_fn_error.kal"))
+        .unwrap();
+    let expected = List(Rc::new(vec![
+        List(Rc::new(vec![
+            Str(Rc::new("invalid".to_owned())),
+            Str(Rc::new("check failed".to_owned())),
+        ])),
+        Int(10),
+    ]));
+    assert!(
+        val == expected,
+        "Assertion failed: got {:?}, expected {:?}.",
+        val,
+        expected
+    );
+}
+
+#[test]
+fn native_fn_callback() {
+    let val = native_runtime()
+        .eval_file(Path::new("tests/native_fn_callback.kal"))
+        .unwrap();
+    let expected = List(Rc::new(vec![Int(18), Int(2)]));
+    assert!(
+        val == expected,
+        "Assertion failed: got {:?}, expected {:?}.",
+        val,
+        expected
+    );
+}
+
+#[test]
+fn native_call_out_of_fuel() {
+    let mut runtime = Interpreter::new();
+    let forever = runtime
+        .eval(crate::parse("fn() loop { 1 }").unwrap())
+        .unwrap();
+    runtime.set_fuel(Some(1000));
+    assert_runtime_error(
+        runtime.call(forever, vec![]),
+        "out_of_fuel",
+        "Ran out of fuel",
+    );
+
+    // the interpreter can still be used afterwards
+    runtime.set_fuel(None);
+    let add = runtime
+        .eval(crate::parse("fn(a, b) a + b").unwrap())
+        .unwrap();
+    assert!(runtime.call(add, vec![Int(1), Int(2)]).unwrap() == Int(3));
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Config {
+    name: String,
+    port: u16,
+    tags: Vec<String>,
+    fallback: Option<i64>,
+    shape: Shape,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+enum Shape {
+    Point,
+    Circle { r: i64 },
+    Pair(i64, i64),
+}
+
+#[test]
+fn convert_round_trip() {
+    for shape in [Shape::Point, Shape::Circle { r: 2 }, Shape::Pair(1, 2)] {
+        let config = Config {
+            name: "kal".to_owned(),
+            port: 8080,
+            tags: vec!["a".to_owned(), "b".to_owned()],
+            fallback: None,
+            shape,
+        };
+        let val = crate::to_value(&config).unwrap();
+        assert_eq!(crate::from_value::<Config>(&val).unwrap(), config);
+    }
+}
+
+#[test]
+fn convert_to_value() {
+    let val =
+        crate::to_value(&(Some(1), None::<i64>, Shape::Point, Shape::Circle { r: 3 })).unwrap();
+    let circle = Object(Rc::new(HashMap::from([(
+        Key::Str("Circle".to_owned()),
+        Object(Rc::new(HashMap::from([(Key::Str("r".to_owned()), Int(3))]))),
+    )])));
+    let expected = List(Rc::new(vec![
+        Int(1),
+        Null,
+        Str(Rc::new("Point".to_owned())),
+        circle,
+    ]));
+    assert!(
+        val == expected,
+        "Assertion failed: got {:?}, expected {:?}.",
+        val,
+        expected
+    );
+    assert!(crate::to_value(&1.5).unwrap() == Float(1.5));
+    assert_eq!(crate::from_value::<f64>(&Float(1.5)).unwrap(), 1.5);
+}
+
+#[test]
+fn convert_from_kal() {
+    let val = crate::eval_source(
+        "{ name: \"kal\", port: 80, tags: [\"x\"], fallback: 4, shape: { Pair: [1, 2] } }",
+    )
+    .unwrap();
+    let config: Config = crate::from_value(&val).unwrap();
+    assert_eq!(
+        config,
+        Config {
+            name: "kal".to_owned(),
+            port: 80,
+            tags: vec!["x".to_owned()],
+            fallback: Some(4),
+            shape: Shape::Pair(1, 2),
+        }
+    );
+}
+
+#[test]
+fn convert_errors() {
+    assert!(crate::from_value::<u8>(&Int(300)).is_err());
+    assert!(crate::from_value::<String>(&Int(1)).is_err());
+    let closure = crate::eval_source("fn() 1").unwrap();
+    assert!(crate::from_value::<i64>(&closure).is_err());
+    assert!(crate::from_value::<i64>(&Float(1.5)).is_err());
+}
+
+#[test]
+fn native_fn_typed() {
+    let mut runtime = Interpreter::new();
+    runtime.register_typed_fn("repeat", |(s, n): (String, usize)| Ok(s.repeat(n)));
+    let val = runtime
+        .eval_file(Path::new("tests/native_fn_typed.kal"))
+        .unwrap();
+    let expected = List(Rc::new(vec![
+        Str(Rc::new("ababab".to_owned())),
+        Str(Rc::new("conversion".to_owned())),
+    ]));
+    assert!(
+        val == expected,
+        "Assertion failed: got {:?}, expected {:?}.",
+        val,
+        expected
+    );
+}
+
+fn parse_file(path: &str) -> crate::Program {
+    let text =
+        std::fs::read_to_string(path).unwrap_or_else(|_| panic!("Could not read file {:?}", path));
+    crate::parse(&text).unwrap()
+}
+
+#[test]
+fn fuel_runs_out() {
+    let mut runtime = Interpreter::new();
+    runtime.set_fuel(Some(1000));
+    runtime.start(parse_file("tests/fuel_forever.kal"));
+    assert_eq!(runtime.run_until_effect(), RunStatus::OutOfFuel);
+    assert_eq!(runtime.fuel(), Some(0));
+
+    // more fuel lets it carry on from where it stopped
+    runtime.set_fuel(Some(1000));
+    assert_eq!(runtime.run_until_effect(), RunStatus::OutOfFuel);
+}
+
+#[test]
+fn fuel_time_slicing() {
+    let mut runtime = Interpreter::new();
+    runtime.start(parse_file("tests/fuel_sum.kal"));
+    let mut slices = 0;
+    let val = loop {
+        slices += 1;
+        runtime.set_fuel(Some(10));
+        if let RunStatus::Finished(val) = runtime.run_until_effect() {
+            break val;
+        }
+    };
+    assert!(val == Int(55));
+    assert!(
+        slices > 10,
+        "Expected the program to be split into many slices, got {}.",
+        slices
+    );
+}
+
+#[test]
+fn fuel_eval() {
+    let mut runtime = Interpreter::new();
+    runtime.set_fuel(Some(1000));
+    assert_runtime_error(
+        runtime.eval_file(Path::new("tests/fuel_forever.kal")),
+        "out_of_fuel",
+        "Ran out of fuel",
+    );
+
+    // the abandoned program doesn't get in the way of the next one
+    runtime.set_fuel(None);
+    assert!(runtime.eval(crate::parse("1 + 2").unwrap()).unwrap() == Int(3));
+}
+
+// Evaluate a file with limits, returning the effects it doesn't handle as errors.
+fn eval_file_limited(path: &str, limits: Limits) -> Result<Value, crate::Error> {
+    let mut runtime = Interpreter::new();
+    runtime.set_limits(limits);
+    let val = runtime.eval_file(Path::new(path))?;
+    crate::check_unhandled_effect(&runtime, val)
+}
+
+#[test]
+fn limits_heap() {
+    let limits = Limits {
+        max_heap_bytes: Some(100_000),
+        ..Limits::default()
+    };
+    assert_runtime_error(
+        eval_file_limited("tests/limits_heap.kal", limits),
+        "resource_exhausted",
+        "bytes of lists",
+    );
+}
+
+#[test]
+fn limits_heap_json() {
+    let limits = Limits {
+        max_heap_bytes: Some(100_000),
+        ..Limits::default()
+    };
+    let source = format!("json.parse(\"[{}]\")", vec!["1"; 20_000].join(","));
+    let mut runtime = Interpreter::new();
+    runtime.set_limits(limits);
+    let val = runtime.eval(crate::parse(&source).unwrap()).unwrap();
+    assert_runtime_error(
+        crate::check_unhandled_effect(&runtime, val),
+        "resource_exhausted",
+        "bytes of lists",
+    );
+}
+
+#[test]
+fn limits_heap_strings() {
+    let limits = Limits {
+        max_heap_bytes: Some(100_000),
+        ..Limits::default()
+    };
+    assert_runtime_error(
+        eval_file_limited("tests/limits_heap_strings.kal", limits),
+        "resource_exhausted",
+        "bytes of lists",
+    );
+}
+
+#[test]
+fn limits_heap_handled() {
+    let limits = Limits {
+        max_heap_bytes: Some(100_000),
+        ..Limits::default()
+    };
+    let val = eval_file_limited("tests/limits_heap_handled.kal", limits).unwrap();
+    // how many times the list could double depends on the size of a value
+    let doublings = match &val {
+        List(list) if list[0] == Str(Rc::new("resource_exhausted".to_owned())) => list[1].clone(),
+        _ => panic!(
+            "Expected the error kind and the number of doublings, got {:?}.",
+            val
+        ),
+    };
+    assert!(
+        matches!(doublings, Int(8..=12)),
+        "Expected the list to double about 10 times, got {:?}.",
+        doublings
+    );
+}
+
+#[test]
+fn limits_heap_freed() {
+    let limits = Limits {
+        max_heap_bytes: Some(10_000),
+        ..Limits::default()
+    };
+    let val = eval_file_limited("tests/limits_heap_freed.kal", limits).unwrap();
+    assert!(
+        val == Int(1000),
+        "Assertion failed: got {:?}, expected {:?}.",
+        val,
+        Int(1000)
+    );
+}
+
+#[test]
+fn limits_channels() {
+    let limits = Limits {
+        max_heap_bytes: Some(10_000),
+        ..Limits::default()
+    };
+    let val = eval_file_limited("tests/limits_channels.kal", limits).unwrap();
+    assert!(
+        val == Str(Rc::new("resource_exhausted".to_owned())),
+        "Assertion failed: got {:?}.",
+        val
+    );
+
+    // closed channels are freed once they are empty
+    let val = eval_file_limited("tests/limits_channels_closed.kal", limits).unwrap();
+    assert!(
+        val == Int(1000),
+        "Assertion failed: got {:?}, expected {:?}.",
+        val,
+        Int(1000)
+    );
+}
+
+#[test]
+fn limits_recursion() {
+    let limits = Limits {
+        max_call_depth: Some(100),
+        ..Limits::default()
+    };
+    let val = eval_file_limited("tests/limits_recursion.kal", limits).unwrap();
+    let expected = List(Rc::new(vec![
+        Str(Rc::new("resource_exhausted".to_owned())),
+        Int(20),
+    ]));
+    assert!(
+        val == expected,
+        "Assertion failed: got {:?}, expected {:?}.",
+        val,
+        expected
+    );
+}
+
+#[test]
+fn limits_value_stack() {
+    let limits = Limits {
+        max_value_stack: Some(8),
+        ..Limits::default()
+    };
+    assert_runtime_error(
+        eval_file_limited("tests/limits_value_stack.kal", limits),
+        "resource_exhausted",
+        "value stack",
+    );
+
+    let limits = Limits {
+        max_value_stack: Some(16),
+        ..Limits::default()
+    };
+    assert!(eval_file_limited("tests/limits_value_stack.kal", limits).is_ok());
+}
+
+#[test]
+fn step_effects() {
+    let mut runtime = Interpreter::new();
+    let read = runtime.global_symbol("read".to_owned());
+    runtime.start(parse_file("tests/step_effects.kal"));
+
+    let status = runtime.run_until_effect();
+    let expected = RunStatus::Suspended(SuspendedEffect {
+        symbol: read.clone(),
+        value: Str(Rc::new("a".to_owned())),
+    });
+    assert_eq!(status, expected);
+    runtime.continue_with(Int(1));
+
+    let status = runtime.run_until_effect();
+    let expected = RunStatus::Suspended(SuspendedEffect {
+        symbol: read,
+        value: Str(Rc::new("b".to_owned())),
+    });
+    assert_eq!(status, expected);
+    runtime.continue_with(Int(2));
+
+    assert_eq!(runtime.run_until_effect(), RunStatus::Finished(Int(3)));
+}
+
+#[test]
+fn step_passthrough() {
+    let mut runtime = Interpreter::new();
+    let log = runtime.global_symbol("log".to_owned());
+    runtime.start(parse_file("tests/step_passthrough.kal"));
+
+    let expected = RunStatus::Suspended(SuspendedEffect {
+        symbol: log,
+        value: Str(Rc::new("hello".to_owned())),
+    });
+    assert_eq!(runtime.run_until_effect(), expected);
+    runtime.continue_with(Int(41));
+    assert_eq!(runtime.run_until_effect(), RunStatus::Finished(Int(42)));
+}
+
+#[test]
+fn step_interleaved() {
+    let mut runtimes = [Interpreter::new(), Interpreter::new()];
+    runtimes[0].start(parse_file("tests/fuel_sum.kal"));
+    runtimes[1].start(parse_file("tests/fibonacci.kal"));
+
+    let mut results = [None, None];
+    let mut rounds = 0;
+    while results.iter().any(Option::is_none) {
+        rounds += 1;
+        for (runtime, result) in runtimes.iter_mut().zip(results.iter_mut()) {
+            if result.is_some() {
+                continue;
+            }
+            match runtime.step(10) {
+                RunStatus::Finished(val) => *result = Some(val),
+                RunStatus::Yielded => (),
+                status => panic!(
+                    "Expected the programs to run without effects, got {:?}.",
+                    status
+                ),
+            }
+        }
+    }
+    assert_eq!(results[0], Some(Int(55)));
+    assert_eq!(results[1], Some(eval_file("tests/fibonacci.kal")));
+    assert!(
+        rounds > 10,
+        "Expected the programs to take many rounds of steps, took {}.",
+        rounds
+    );
+}
+
+#[test]
+#[should_panic(expected = "isn't suspended at an effect")]
+fn step_continue_not_suspended() {
+    let mut runtime = Interpreter::new();
+    runtime.start(parse_file("tests/fuel_sum.kal"));
+    runtime.continue_with(Null);
+}
+
+#[test]
+fn replay_restore() {
+    let source = std::fs::read_to_string("tests/replay_workflow.kal").unwrap();
+    let mut runtime = Interpreter::new();
+    let wait = runtime.global_symbol("wait".to_owned());
+    runtime.start_recorded(&source).unwrap();
+
+    assert_eq!(
+        runtime.run_until_effect(),
+        RunStatus::Suspended(SuspendedEffect {
+            symbol: wait.clone(),
+            value: Int(1)
+        })
+    );
+    runtime.continue_with(Int(10));
+    assert_eq!(
+        runtime.run_until_effect(),
+        RunStatus::Suspended(SuspendedEffect {
+            symbol: wait.clone(),
+            value: Int(2)
+        })
+    );
+    let bytes = runtime.replay_log().unwrap();
+    drop(runtime);
+
+    // carry on in a fresh interpreter, as if it was another process
+    let mut runtime = Interpreter::new();
+    let status = runtime.replay(&bytes).unwrap();
+    assert_eq!(
+        status,
+        RunStatus::Suspended(SuspendedEffect {
+            symbol: wait.clone(),
+            value: Int(2)
+        })
+    );
+    runtime.continue_with(Int(20));
+    assert_eq!(
+        runtime.run_until_effect(),
+        RunStatus::Suspended(SuspendedEffect {
+            symbol: wait,
+            value: Int(3)
+        })
+    );
+    runtime.continue_with(Int(30));
+    assert_eq!(runtime.run_until_effect(), RunStatus::Finished(Int(60)));
+}
+
+#[test]
+fn replay_log_errors() {
+    let source = std::fs::read_to_string("tests/replay_workflow.kal").unwrap();
+    let mut runtime = Interpreter::new();
+
+    // not suspended yet
+    runtime.start_recorded(&source).unwrap();
+    assert_runtime_error(runtime.replay_log(), "replay", "suspended at an effect");
+
+    // answered with a value that isn't JSON
+    let wait_twice = "let wait = symbol_for(\"wait\"); send wait with 1; send wait with 2";
+    runtime.start_recorded(wait_twice).unwrap();
+    runtime.run_until_effect();
+    let symbol = runtime.global_symbol("answer".to_owned());
+    runtime.continue_with(symbol);
+    runtime.run_until_effect();
+    assert_runtime_error(runtime.replay_log(), "replay", "can't be logged");
+
+    // not started with start_recorded
+    runtime.start(crate::parse(&source).unwrap());
+    runtime.run_until_effect();
+    assert_runtime_error(
+        runtime.replay_log(),
+        "replay",
+        "Only programs started with start_recorded",
+    );
+}
+
+#[test]
+fn replay_mismatch() {
+    let bytes = br#"{ "source": "let x = 5; x", "answers": [1] }"#;
+    let mut runtime = Interpreter::new();
+    assert_runtime_error(
+        runtime.replay(bytes),
+        "replay",
+        "didn't send the same effects",
+    );
+    assert_runtime_error(runtime.replay(b"[]"), "replay", "Invalid replay log");
+}
+
+fn str_list(strs: &[&str]) -> Value {
+    List(Rc::new(
+        strs.iter().map(|s| Str(Rc::new(s.to_string()))).collect(),
+    ))
+}
+
+test! { tasks_spawn_await, List(Rc::new(vec![Int(20), Int(10), Int(10)])) }
+test! { tasks_sleep, List(Rc::new(vec![
+    str_list(&["slow", "fast"]),
+    List(Rc::new(vec![
+        List(Rc::new(vec![Str(Rc::new("fast".to_owned())), Int(10)])),
+        List(Rc::new(vec![Str(Rc::new("slow".to_owned())), Int(30)])),
+    ])),
+    Int(30),
+])) }
+#[test]
+fn tasks_unknown() {
+    // a task which belongs to another interpreter, as far as this one knows
+    let mut runtime = Interpreter::new();
+    runtime.register_fn("forged_task", |_args| Ok(Task(1000)));
+    let val = runtime
+        .eval_file(Path::new("tests/tasks_unknown.kal"))
+        .unwrap();
+    let expected = List(Rc::new(vec![
+        Str(Rc::new("task".to_owned())),
+        Str(Rc::new("There is no task 1000 to wait for.".to_owned())),
+    ]));
+    assert!(
+        val == expected,
+        "Assertion failed: got {:?}, expected {:?}.",
+        val,
+        expected
+    );
+}
+test! { tasks_round_robin, str_list(&["a", "b", "a", "b", "a", "b"]) }
+test! { tasks_deadlock, List(Rc::new(vec![
+    Str(Rc::new("deadlock".to_owned())),
+    Str(Rc::new("Deadlock: every task is waiting for something that will never happen.".to_owned())),
+    Int(5),
+])) }
+
+test! { channels_pipeline, List(Rc::new(vec![Int(2), Int(4), Int(6), Int(8)])) }
+test! { channels_buffered, List(Rc::new(vec![str_list(&["sent", "other"]), Int(1), Int(2)])) }
+test! { channels_select, List(Rc::new(vec![
+    List(Rc::new(vec![Int(1), Str(Rc::new("fast".to_owned()))])),
+    Null,
+    List(Rc::new(vec![Int(0), Str(Rc::new("slow".to_owned()))])),
+    Int(50),
+])) }
+test! { channels_closed, List(Rc::new(vec![
+    Int(1),
+    Str(Rc::new("channel_closed".to_owned())),
+    Str(Rc::new("channel_closed".to_owned())),
+])) }
+test! { channels_backpressure, List(Rc::new(vec![
+    List(Rc::new(vec![Str(Rc::new("sent".to_owned())), Int(1), Int(0)])),
+    List(Rc::new(vec![Str(Rc::new("sent".to_owned())), Int(2), Int(0)])),
+    List(Rc::new(vec![Str(Rc::new("received".to_owned())), Int(1), Int(10)])),
+    List(Rc::new(vec![Str(Rc::new("sent".to_owned())), Int(3), Int(10)])),
+    List(Rc::new(vec![Str(Rc::new("received".to_owned())), Int(2), Int(20)])),
+    List(Rc::new(vec![Str(Rc::new("received".to_owned())), Int(3), Int(30)])),
+])) }
+
+fn host_config() -> Value {
+    Object(Rc::new(HashMap::from([
+        (Key::Str("name".to_owned()), Str(Rc::new("kal".to_owned()))),
+        (
+            Key::Str("greeting".to_owned()),
+            Str(Rc::new("hello".to_owned())),
+        ),
+        (Key::Str("n".to_owned()), Int(21)),
+    ])))
+}
+
+#[test]
+fn manifest_start() {
+    let mut runtime = Interpreter::new();
+    runtime.grant("host::config", host_config());
+    let val = runtime
+        .start_file(Path::new("tests/manifest_start.kal"))
+        .unwrap();
+    let expected = List(Rc::new(vec![
+        List(Rc::new(vec![
+            Str(Rc::new("hello".to_owned())),
+            Str(Rc::new("kal".to_owned())),
+        ])),
+        Int(42),
+    ]));
+    assert!(
+        val == expected,
+        "Assertion failed: got {:?}, expected {:?}.",
+        val,
+        expected
+    );
+}
+
+#[test]
+fn manifest_not_granted() {
+    let mut runtime = Interpreter::new();
+    assert_runtime_error(
+        runtime.start_file(Path::new("tests/manifest_start.kal")),
+        "capability",
+        "because the host hasn't granted host::config",
+    );
+}
+
+#[test]
+fn manifest_without_imports() {
+    let mut runtime = Interpreter::new();
+    let val = runtime
+        .start_file(Path::new("tests/import_module.kal"))
+        .unwrap();
+    assert!(val == eval_file("tests/import_module.kal"));
+}